@@ -1,5 +1,10 @@
+pub mod blocklist;
 pub mod epl_standing;
+pub mod forget;
+pub mod gallery;
 pub mod imagine;
 pub mod math;
 pub mod meta;
+pub mod persona;
 pub mod rw;
+pub mod usage;
@@ -0,0 +1,273 @@
+use serenity::{
+    builder::{CreateApplicationCommand, CreateApplicationCommandOption},
+    model::{
+        application::interaction::{
+            application_command::{
+                ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+            },
+            InteractionResponseType,
+        },
+        id::GuildId,
+        prelude::command::CommandOptionType,
+        Permissions,
+    },
+    prelude::Context,
+};
+use tracing::error;
+
+use crate::utils::{
+    chunker::{split_message, DISCORD_MESSAGE_LIMIT},
+    moderation::{
+        add_rule, get_audit_log, get_rules, remove_rule, AuditEntry, BlockRule, MAX_PATTERN_LENGTH,
+        MAX_RULES,
+    },
+    outbound::{escape_mass_mentions, only_user},
+    redis_client::{RedisClient, RedisManager},
+};
+
+/// Blocked prompts `/blocklist log` shows.
+const LOG_LENGTH: isize = 10;
+
+fn get_option<'a>(
+    options: &'a [CommandDataOption],
+    name: &str,
+) -> Option<&'a CommandDataOptionValue> {
+    options
+        .iter()
+        .find(|option| option.name == name)?
+        .resolved
+        .as_ref()
+}
+
+/// The rule given to `/blocklist add` or `/blocklist remove`.
+fn get_rule(options: &[CommandDataOption]) -> Option<BlockRule> {
+    let pattern = match get_option(options, "pattern")? {
+        CommandDataOptionValue::String(pattern) => pattern.trim().to_string(),
+        _ => return None,
+    };
+    let is_regex = matches!(
+        get_option(options, "regex"),
+        Some(CommandDataOptionValue::Boolean(true))
+    );
+    Some(if is_regex {
+        BlockRule::Regex(pattern)
+    } else {
+        BlockRule::Keyword(pattern)
+    })
+}
+
+fn format_rules(rules: &[BlockRule]) -> String {
+    if rules.is_empty() {
+        return "Nothing is blocked in this server.".to_string();
+    }
+    let lines: Vec<String> = rules
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| format!("{}. {}", index + 1, rule.describe()))
+        .collect();
+    format!("Blocked in this server:\n{}", lines.join("\n"))
+}
+
+fn format_entry(entry: &AuditEntry) -> String {
+    format!(
+        "<t:{}:R> <@{}> in <#{}> ({}), {}:\n> {}",
+        entry.timestamp,
+        entry.user_id,
+        entry.channel_id,
+        entry.feature,
+        entry.reason.describe(),
+        entry.excerpt.replace('\n', " ")
+    )
+}
+
+async fn handle(
+    conn: &mut RedisManager,
+    subcommand: &CommandDataOption,
+    guild_id: GuildId,
+) -> String {
+    match subcommand.name.as_str() {
+        "add" => {
+            let Some(rule) = get_rule(&subcommand.options) else {
+                return "Give a pattern to block.".to_string();
+            };
+            if let Err(e) = rule.validate() {
+                return e;
+            }
+            match get_rules(conn, guild_id).await {
+                Ok(rules) if rules.len() >= MAX_RULES => {
+                    return format!("A server can block at most {} patterns.", MAX_RULES)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to load blocklist: {}", e);
+                    return "Failed to load the blocklist, please try again.".to_string();
+                }
+            }
+            match add_rule(conn, guild_id, &rule).await {
+                Ok(true) => format!("Blocked {}.", rule.describe()),
+                Ok(false) => format!("The {} is already blocked.", rule.describe()),
+                Err(e) => {
+                    error!("Failed to add block rule: {}", e);
+                    "Failed to save the blocklist, please try again.".to_string()
+                }
+            }
+        }
+        "remove" => {
+            let Some(rule) = get_rule(&subcommand.options) else {
+                return "Give the pattern to unblock.".to_string();
+            };
+            match remove_rule(conn, guild_id, &rule).await {
+                Ok(true) => format!("Unblocked {}.", rule.describe()),
+                Ok(false) => format!("The {} isn't blocked.", rule.describe()),
+                Err(e) => {
+                    error!("Failed to remove block rule: {}", e);
+                    "Failed to save the blocklist, please try again.".to_string()
+                }
+            }
+        }
+        "list" => match get_rules(conn, guild_id).await {
+            Ok(rules) => format_rules(&rules),
+            Err(e) => {
+                error!("Failed to load blocklist: {}", e);
+                "Failed to load the blocklist, please try again.".to_string()
+            }
+        },
+        "log" => match get_audit_log(conn, guild_id, LOG_LENGTH).await {
+            Ok(entries) if entries.is_empty() => "Nothing has been blocked yet.".to_string(),
+            Ok(entries) => entries
+                .iter()
+                .map(format_entry)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Err(e) => {
+                error!("Failed to load moderation log: {}", e);
+                "Failed to load the log, please try again.".to_string()
+            }
+        },
+        _ => "Unknown subcommand".to_string(),
+    }
+}
+
+pub async fn run(ctx: Context, command: ApplicationCommandInteraction) {
+    let is_admin = command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.administrator());
+
+    let content = match (command.guild_id, command.data.options.first()) {
+        (Some(_), _) if !is_admin => "Only admins can manage the blocklist.".to_string(),
+        (Some(guild_id), Some(subcommand)) => {
+            let mut conn = {
+                let data = ctx.data.read().await;
+                data.get::<RedisClient>().unwrap().clone()
+            };
+            handle(&mut conn, subcommand, guild_id).await
+        }
+        _ => "The blocklist can only be managed in a server.".to_string(),
+    };
+
+    // Long lists and logs continue in follow-ups
+    let content = escape_mass_mentions(content);
+    let mut chunks = split_message(&content, DISCORD_MESSAGE_LIMIT).into_iter();
+    let first = chunks.next().unwrap_or_default();
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    // The log quotes prompts and names their authors, don't ping them
+                    message
+                        .content(first)
+                        .allowed_mentions(|am| only_user(am, command.user.id))
+                        .ephemeral(true)
+                })
+        })
+        .await
+    {
+        error!("Cannot respond to slash command: {}", why);
+        return;
+    }
+    for chunk in chunks {
+        if let Err(why) = command
+            .create_followup_message(&ctx.http, |followup| {
+                followup
+                    .content(chunk)
+                    .allowed_mentions(|am| only_user(am, command.user.id))
+                    .ephemeral(true)
+            })
+            .await
+        {
+            error!("Cannot send follow-up: {}", why);
+            return;
+        }
+    }
+}
+
+fn rule_options(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .kind(CommandOptionType::SubCommand)
+        .create_sub_option(|option| {
+            option
+                .name("pattern")
+                .description("A keyword, or a regex")
+                .kind(CommandOptionType::String)
+                .max_length(MAX_PATTERN_LENGTH)
+                .required(true)
+        })
+        .create_sub_option(|option| {
+            option
+                .name("regex")
+                .description("Treat the pattern as a regex")
+                .kind(CommandOptionType::Boolean)
+        })
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("blocklist")
+        .description("Manage what can't be asked of the bot in this server")
+        .default_member_permissions(Permissions::ADMINISTRATOR)
+        .dm_permission(false)
+        .create_option(|option| {
+            rule_options(
+                option
+                    .name("add")
+                    .description("Refuse prompts matching a keyword or regex"),
+            )
+        })
+        .create_option(|option| {
+            rule_options(option.name("remove").description("Stop refusing a pattern"))
+        })
+        .create_option(|option| {
+            option
+                .name("list")
+                .description("List the blocked patterns")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            option
+                .name("log")
+                .description("Show the most recently refused prompts")
+                .kind(CommandOptionType::SubCommand)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_rules() {
+        assert_eq!(format_rules(&[]), "Nothing is blocked in this server.");
+        assert_eq!(
+            format_rules(&[
+                BlockRule::Keyword("crypto".to_string()),
+                BlockRule::Regex(r"nft\d*".to_string()),
+            ]),
+            "Blocked in this server:\n1. keyword `crypto`\n2. regex `nft\\d*`"
+        );
+    }
+}
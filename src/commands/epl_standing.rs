@@ -1,11 +1,17 @@
-use crate::libs::epl_data_client::{get_standings, TeamStanding};
+use crate::libs::epl_data_client::TeamStanding;
+use crate::utils::{
+    redis_client::RedisClient,
+    standings_cache::{get_cached_standings, Standings},
+};
 use comfy_table::Table;
 use comfy_table::{presets::ASCII_HORIZONTAL_ONLY, ContentArrangement};
 use serenity::{
     builder::{CreateApplicationCommand, CreateEmbed},
     model::application::interaction::application_command::ApplicationCommandInteraction,
+    model::Timestamp,
     prelude::Context,
 };
+use tracing::error;
 
 const DISCORD_ROW_LIMIT: u16 = 56;
 pub fn format_standings(standings: &[TeamStanding]) -> String {
@@ -37,27 +43,82 @@ pub fn format_standings(standings: &[TeamStanding]) -> String {
 }
 
 pub async fn run(ctx: Context, command: ApplicationCommandInteraction) {
-    command.defer(&ctx).await.unwrap();
-    let standings_response = get_standings().await.unwrap();
+    if let Err(why) = command.defer(&ctx).await {
+        error!("Cannot respond to slash command: {}", why);
+        return;
+    }
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
 
-    let formatted_standings = format_standings(&standings_response.standing);
-    let embed = create_standings_embed(&formatted_standings, &standings_response.updated_at);
+    let embed = match get_cached_standings(&mut conn).await {
+        Ok(Standings::Fresh(standings)) => create_standings_embed(
+            &format_standings(&standings.standing),
+            &standings.updated_at,
+            None,
+        ),
+        Ok(Standings::Stale {
+            standings,
+            fetched_at,
+        }) => create_standings_embed(
+            &format_standings(&standings.standing),
+            &standings.updated_at,
+            Some(fetched_at),
+        ),
+        Err(e) => {
+            error!("Failed to fetch standings: {}", e);
+            create_error_embed()
+        }
+    };
 
     if let Err(why) = command
         .edit_original_interaction_response(&ctx.http, |response| response.add_embed(embed))
         .await
     {
-        println!("Cannot edit response: {}", why);
+        error!("Cannot edit response: {}", why);
     }
 }
 
-fn create_standings_embed(standings: &str, updated_at: &str) -> CreateEmbed {
+/// `stale_since` is when standings served from the cache were fetched, if
+/// they couldn't be brought up to date.
+fn create_standings_embed(
+    standings: &str,
+    updated_at: &str,
+    stale_since: Option<i64>,
+) -> CreateEmbed {
     let mut embed = CreateEmbed::default();
     embed
         .title("積分榜")
         .description(format!("```\n{}\n```", standings))
-        .footer(|f| f.text(format!("最後更新: {}", updated_at)))
         .color(0x3498db);
+    match stale_since {
+        Some(fetched_at) => {
+            embed
+                .footer(|f| {
+                    f.text(format!(
+                        "⚠️ 暫時無法更新，資料可能已過時 · 最後更新: {}",
+                        updated_at
+                    ))
+                })
+                .color(0xf1c40f);
+            if let Ok(timestamp) = Timestamp::from_unix_timestamp(fetched_at) {
+                embed.timestamp(timestamp);
+            }
+        }
+        None => {
+            embed.footer(|f| f.text(format!("最後更新: {}", updated_at)));
+        }
+    }
+    embed
+}
+
+fn create_error_embed() -> CreateEmbed {
+    let mut embed = CreateEmbed::default();
+    embed
+        .title("積分榜")
+        .description("暫時無法取得積分榜，請稍後再試。")
+        .color(0xe74c3c);
     embed
 }
 
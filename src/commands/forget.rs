@@ -0,0 +1,166 @@
+use redis::RedisResult;
+use serenity::{
+    builder::CreateApplicationCommand,
+    model::application::interaction::{
+        application_command::{
+            ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+        },
+        InteractionResponseType,
+    },
+    model::prelude::command::CommandOptionType,
+    prelude::Context,
+};
+use tracing::error;
+
+use crate::utils::{
+    chat_store::{
+        delete_channel_threads, delete_thread, delete_user_threads, get_last_thread, get_thread_of,
+        is_thread_of_user,
+    },
+    redis_client::{RedisClient, RedisManager},
+};
+
+/// Message ID from either a bare ID or a message link, whose last segment is
+/// the message ID.
+fn parse_message_id(value: &str) -> Option<u64> {
+    value
+        .trim()
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+async fn forget_thread(
+    conn: &mut RedisManager,
+    command: &ApplicationCommandInteraction,
+    options: &[CommandDataOption],
+    is_admin: bool,
+) -> RedisResult<String> {
+    let message = options
+        .iter()
+        .find(|option| option.name == "message")
+        .and_then(|option| option.resolved.as_ref());
+    let chat_key = match message {
+        Some(CommandDataOptionValue::String(message)) => match parse_message_id(message) {
+            Some(message_id) => get_thread_of(conn, message_id).await?,
+            None => return Ok("That is not a message link or ID.".to_string()),
+        },
+        _ => get_last_thread(conn, command.user.id, command.channel_id).await?,
+    };
+    let Some(chat_key) = chat_key else {
+        return Ok("There is no conversation to forget.".to_string());
+    };
+
+    if !is_admin && !is_thread_of_user(conn, &chat_key, command.user.id).await? {
+        return Ok("You can only forget conversations you started.".to_string());
+    }
+    delete_thread(conn, &chat_key).await?;
+    Ok("Forgot that conversation.".to_string())
+}
+
+async fn forget(
+    conn: &mut RedisManager,
+    command: &ApplicationCommandInteraction,
+    subcommand: &CommandDataOption,
+    is_admin: bool,
+) -> RedisResult<String> {
+    match subcommand.name.as_str() {
+        "thread" => forget_thread(conn, command, &subcommand.options, is_admin).await,
+        "mine" => {
+            let deleted = delete_user_threads(conn, command.user.id).await?;
+            Ok(format!("Forgot {} of your conversations.", deleted))
+        }
+        "channel" if is_admin => {
+            let deleted = delete_channel_threads(conn, command.channel_id).await?;
+            Ok(format!("Forgot {} conversations in this channel.", deleted))
+        }
+        "channel" => Ok("Only admins can forget a whole channel.".to_string()),
+        _ => Ok("Unknown subcommand".to_string()),
+    }
+}
+
+pub async fn run(ctx: Context, command: ApplicationCommandInteraction) {
+    let is_admin = command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.administrator());
+
+    let content = match command.data.options.first() {
+        Some(subcommand) => {
+            let mut conn = {
+                let data = ctx.data.read().await;
+                data.get::<RedisClient>().unwrap().clone()
+            };
+            forget(&mut conn, &command, subcommand, is_admin)
+                .await
+                .unwrap_or_else(|e| {
+                    error!("Failed to forget: {}", e);
+                    "Failed to forget, please try again.".to_string()
+                })
+        }
+        None => "Unknown subcommand".to_string(),
+    };
+
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| message.content(content).ephemeral(true))
+        })
+        .await
+    {
+        error!("Cannot respond to slash command: {}", why);
+    }
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("forget")
+        .description("Delete stored conversations with the bot")
+        .create_option(|option| {
+            option
+                .name("thread")
+                .description("Forget one conversation, your last one here by default")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|option| {
+                    option
+                        .name("message")
+                        .description("Link or ID of any message in the conversation")
+                        .kind(CommandOptionType::String)
+                })
+        })
+        .create_option(|option| {
+            option
+                .name("mine")
+                .description("Forget every conversation you started")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            option
+                .name("channel")
+                .description("Forget every conversation started in this channel (admins only)")
+                .kind(CommandOptionType::SubCommand)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message_id() {
+        assert_eq!(parse_message_id("123"), Some(123));
+        assert_eq!(
+            parse_message_id("https://discord.com/channels/1/2/345"),
+            Some(345)
+        );
+        assert_eq!(
+            parse_message_id("https://discord.com/channels/1/2/345/"),
+            Some(345)
+        );
+        assert_eq!(parse_message_id("hello"), None);
+    }
+}
@@ -0,0 +1,424 @@
+use serenity::{
+    builder::{CreateApplicationCommand, CreateComponents, CreateEmbed},
+    model::application::{
+        component::ButtonStyle,
+        interaction::{
+            application_command::{
+                ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+            },
+            message_component::MessageComponentInteraction,
+            InteractionResponseType,
+        },
+    },
+    model::prelude::{command::CommandOptionType, UserId},
+    model::Timestamp,
+    prelude::Context,
+};
+use std::sync::Arc;
+use tracing::error;
+
+use crate::commands::imagine::get_throttle;
+use crate::utils::{
+    gallery::{
+        filter_entries, get_entries, record_upload, GalleryEntry, GalleryScope, UploadedImages,
+    },
+    image_queue::{take_turn, ImageQueue, JobQueue, Ticket},
+    image_upload::{build_uploads, too_large_message, truncate, EMBED_TITLE_LIMIT},
+    moderation::{check_prompt, PromptSource},
+    openai::generate_images,
+    outbound::only_user,
+    rate_limit::{throttled_message, Feature},
+    redis_client::{RedisClient, RedisManager},
+    usage::{check_quota, try_record_usage, UsageRecord},
+};
+
+const PAGE_ID_PREFIX: &str = "gallery:";
+const REROLL_ID_PREFIX: &str = "gallery_reroll:";
+/// Keeps the keyword short enough for button ids, which hold 100 characters.
+const MAX_KEYWORD_LENGTH: u16 = 50;
+
+/// What `/gallery` was asked to show, carried along in the buttons' ids.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct GalleryFilter {
+    author_id: Option<UserId>,
+    keyword: Option<String>,
+}
+
+impl GalleryFilter {
+    /// Id of a button that shows `page` of the filtered gallery.
+    fn page_id(&self, page: usize) -> String {
+        format!(
+            "{}{}:{}:{}",
+            PAGE_ID_PREFIX,
+            page,
+            self.author_id.map_or(0, |author_id| author_id.0),
+            self.keyword.as_deref().unwrap_or_default()
+        )
+    }
+
+    fn parse_page_id(custom_id: &str) -> Option<(Self, usize)> {
+        let mut parts = custom_id.strip_prefix(PAGE_ID_PREFIX)?.splitn(3, ':');
+        let page = parts.next()?.parse().ok()?;
+        let author_id: u64 = parts.next()?.parse().ok()?;
+        let keyword = parts.next()?;
+        let filter = GalleryFilter {
+            author_id: (author_id != 0).then_some(UserId(author_id)),
+            keyword: (!keyword.is_empty()).then(|| keyword.to_string()),
+        };
+        Some((filter, page))
+    }
+}
+
+/// Loads a fresh link for the image on `page`, as the stored one may have
+/// expired.
+async fn refresh_url(ctx: &Context, entries: &mut [GalleryEntry], page: usize) {
+    if let Some(entry) = entries.get_mut(page) {
+        entry.url = entry.fresh_url(&ctx.http).await;
+    }
+}
+
+fn build_embed(entries: &[GalleryEntry], page: usize) -> CreateEmbed {
+    let mut embed = CreateEmbed::default();
+    let Some(entry) = entries.get(page) else {
+        embed.title("Gallery").description("No images found.");
+        return embed;
+    };
+
+    embed
+        .title(truncate(&entry.prompt, EMBED_TITLE_LIMIT))
+        .image(&entry.url)
+        .field("By", format!("<@{}>", entry.author_id), true)
+        .footer(|footer| footer.text(format!("Image {} of {}", page + 1, entries.len())));
+    if let Some(revised_prompt) = &entry.revised_prompt {
+        embed.description(revised_prompt);
+    }
+    if let Ok(timestamp) = Timestamp::from_unix_timestamp(entry.timestamp) {
+        embed.timestamp(timestamp);
+    }
+    embed
+}
+
+fn build_components(
+    filter: &GalleryFilter,
+    entries: &[GalleryEntry],
+    page: usize,
+) -> CreateComponents {
+    let mut components = CreateComponents::default();
+    components.create_action_row(|row| {
+        row.create_button(|button| {
+            button
+                .custom_id(filter.page_id(page.saturating_sub(1)))
+                .label("Previous")
+                .style(ButtonStyle::Secondary)
+                .disabled(page == 0)
+        })
+        .create_button(|button| {
+            button
+                .custom_id(filter.page_id(page + 1))
+                .label("Next")
+                .style(ButtonStyle::Secondary)
+                .disabled(page + 1 >= entries.len())
+        });
+        let entry = entries.get(page);
+        row.create_button(|button| {
+            button
+                .custom_id(format!(
+                    "{}{}",
+                    REROLL_ID_PREFIX,
+                    entry.map_or(0, |entry| entry.id)
+                ))
+                .label("Re-roll")
+                .style(ButtonStyle::Primary)
+                .disabled(entry.is_none_or(|entry| entry.options.is_none()))
+        })
+    });
+    components
+}
+
+async fn load_page(
+    conn: &mut RedisManager,
+    scope: GalleryScope,
+    filter: &GalleryFilter,
+) -> Vec<GalleryEntry> {
+    match get_entries(conn, scope).await {
+        Ok(entries) => filter_entries(entries, filter.author_id, filter.keyword.as_deref()),
+        Err(e) => {
+            error!("Failed to load gallery: {}", e);
+            vec![]
+        }
+    }
+}
+
+fn get_filter(options: &[CommandDataOption]) -> GalleryFilter {
+    let mut filter = GalleryFilter::default();
+    for option in options {
+        match (option.name.as_str(), &option.resolved) {
+            ("user", Some(CommandDataOptionValue::User(user, _))) => {
+                filter.author_id = Some(user.id)
+            }
+            ("keyword", Some(CommandDataOptionValue::String(keyword))) => {
+                filter.keyword = Some(keyword.replace(':', " ").trim().to_string())
+            }
+            _ => {}
+        }
+    }
+    filter
+}
+
+pub async fn run(ctx: Context, command: ApplicationCommandInteraction) {
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
+    let filter = get_filter(&command.data.options);
+    let scope = GalleryScope::new(command.guild_id, command.channel_id);
+    let mut entries = load_page(&mut conn, scope, &filter).await;
+    refresh_url(&ctx, &mut entries, 0).await;
+
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    message
+                        .set_embed(build_embed(&entries, 0))
+                        .set_components(build_components(&filter, &entries, 0))
+                        .ephemeral(true)
+                })
+        })
+        .await
+    {
+        error!("Cannot respond to slash command: {}", why);
+    }
+}
+
+/// Handles the buttons under a `/gallery` page. Returns false if the
+/// component isn't one of them.
+pub async fn handle_component(ctx: &Context, component: &MessageComponentInteraction) -> bool {
+    let custom_id = component.data.custom_id.as_str();
+    if let Some((filter, page)) = GalleryFilter::parse_page_id(custom_id) {
+        show_page(ctx, component, filter, page).await;
+        true
+    } else if let Some(entry_id) = custom_id.strip_prefix(REROLL_ID_PREFIX) {
+        let entry_id = entry_id.parse().unwrap_or_default();
+        reroll(ctx, component, entry_id).await;
+        true
+    } else {
+        false
+    }
+}
+
+async fn show_page(
+    ctx: &Context,
+    component: &MessageComponentInteraction,
+    filter: GalleryFilter,
+    page: usize,
+) {
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
+    let scope = GalleryScope::new(component.guild_id, component.channel_id);
+    let mut entries = load_page(&mut conn, scope, &filter).await;
+    // Images may have been added or dropped since the page was shown
+    let page = page.min(entries.len().saturating_sub(1));
+    refresh_url(ctx, &mut entries, page).await;
+
+    if let Err(why) = component
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|message| {
+                    message
+                        .set_embed(build_embed(&entries, page))
+                        .set_components(build_components(&filter, &entries, page))
+                })
+        })
+        .await
+    {
+        error!("Cannot update gallery: {}", why);
+    }
+}
+
+/// Draws the prompt of a gallery image again with the same settings and
+/// posts the result in the channel. The clicker is told how it went.
+async fn reroll(ctx: &Context, component: &MessageComponentInteraction, entry_id: u64) {
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
+    let throttle = get_throttle(
+        &mut conn,
+        component.user.id,
+        component
+            .member
+            .as_ref()
+            .map(|member| member.roles.as_slice())
+            .unwrap_or_default(),
+        component.channel_id,
+    )
+    .await;
+    if let Some(wait) = throttle {
+        if let Err(why) = component
+            .create_interaction_response(&ctx.http, |response| {
+                response
+                    .kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|message| {
+                        message.content(throttled_message(wait)).ephemeral(true)
+                    })
+            })
+            .await
+        {
+            error!("Cannot respond to button: {}", why);
+        }
+        return;
+    }
+
+    if let Err(why) = component
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|message| message.ephemeral(true))
+        })
+        .await
+    {
+        error!("Cannot respond to button: {}", why);
+        return;
+    }
+
+    let queue = {
+        let data = ctx.data.read().await;
+        data.get::<ImageQueue>().unwrap().clone()
+    };
+    let mut ticket = None;
+    let content = redraw(ctx, &mut conn, component, entry_id, &queue, &mut ticket)
+        .await
+        .unwrap_or_else(|message| message);
+    if let Err(why) = component
+        .edit_original_interaction_response(&ctx.http, |response| response.content(content))
+        .await
+    {
+        error!("Cannot respond to button: {}", why);
+    }
+}
+
+async fn redraw(
+    ctx: &Context,
+    conn: &mut RedisManager,
+    component: &MessageComponentInteraction,
+    entry_id: u64,
+    queue: &Arc<JobQueue>,
+    ticket: &mut Option<Ticket>,
+) -> Result<String, String> {
+    let scope = GalleryScope::new(component.guild_id, component.channel_id);
+    let entry = get_entries(conn, scope)
+        .await
+        .map_err(|e| {
+            error!("Failed to load gallery: {}", e);
+            "Couldn't load the gallery, please try again.".to_string()
+        })?
+        .into_iter()
+        .find(|entry| entry.id == entry_id)
+        .ok_or_else(|| "That image is no longer in the gallery.".to_string())?;
+    let options = entry
+        .options
+        .ok_or_else(|| "Images drawn from an upload can't be re-rolled.".to_string())?;
+    options.validate()?;
+    // The blocklist may have grown since it was drawn
+    let source = PromptSource {
+        user_id: component.user.id,
+        guild_id: component.guild_id,
+        channel_id: component.channel_id,
+        feature: Feature::Imagine,
+    };
+    check_prompt(conn, &source, &entry.prompt).await?;
+    check_quota(conn, component.user.id, component.guild_id).await?;
+
+    let permit = take_turn(queue, ticket, |content| {
+        component
+            .edit_original_interaction_response(&ctx.http, |response| response.content(content))
+    })
+    .await
+    .map_err(|e| e.friendly_message().to_string())?;
+    let images = generate_images(&entry.prompt, &options)
+        .await
+        .map_err(|e| e.friendly_message().to_string())?;
+    drop(permit);
+    let usage = UsageRecord::images(&options.model, images.len());
+    try_record_usage(conn, component.user.id, component.guild_id, &[usage]).await;
+
+    let settings = options.describe();
+    let (uploads, too_large) = build_uploads(&images, &entry.prompt, &settings);
+    let upload = UploadedImages {
+        prompt: &entry.prompt,
+        images: &images,
+        author_id: component.user.id,
+        options: Some(&options),
+    };
+    for (files, embeds) in uploads {
+        let message = component
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.content(format!("Re-rolled by <@{}>", component.user.id))
+                    .allowed_mentions(|am| only_user(am, component.user.id))
+                    .add_files(files)
+                    .add_embeds(embeds)
+            })
+            .await
+            .map_err(|e| {
+                error!("Cannot upload images: {}", e);
+                "Couldn't post the images, please try again.".to_string()
+            })?;
+        if let Err(e) = record_upload(conn, scope, &message, &upload).await {
+            error!("Failed to record images in the gallery: {}", e);
+        }
+    }
+
+    Ok(too_large_message(&too_large).unwrap_or_else(|| "Re-rolled!".to_string()))
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("gallery")
+        .description("Browse images drawn with /imagine")
+        .create_option(|option| {
+            option
+                .name("user")
+                .description("Only show images drawn by this user")
+                .kind(CommandOptionType::User)
+        })
+        .create_option(|option| {
+            option
+                .name("keyword")
+                .description("Only show images whose prompt contains this")
+                .kind(CommandOptionType::String)
+                .max_length(MAX_KEYWORD_LENGTH)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_id_round_trip() {
+        let filter = GalleryFilter {
+            author_id: Some(UserId(10)),
+            keyword: Some("space cat".to_string()),
+        };
+        assert_eq!(filter.page_id(3), "gallery:3:10:space cat");
+        assert_eq!(
+            GalleryFilter::parse_page_id(&filter.page_id(3)),
+            Some((filter, 3))
+        );
+
+        let filter = GalleryFilter::default();
+        assert_eq!(filter.page_id(0), "gallery:0:0:");
+        assert_eq!(
+            GalleryFilter::parse_page_id(&filter.page_id(0)),
+            Some((filter, 0))
+        );
+
+        assert_eq!(GalleryFilter::parse_page_id("gallery_reroll:5"), None);
+    }
+}
@@ -1,39 +1,405 @@
-use serenity::builder::CreateApplicationCommand;
+use serenity::builder::{CreateApplicationCommand, CreateApplicationCommandOption};
+use serenity::model::channel::Attachment;
+use serenity::model::id::{ChannelId, RoleId, UserId};
 use serenity::model::prelude::command::CommandOptionType;
 use serenity::model::prelude::interaction::application_command::{
-    CommandDataOption, CommandDataOptionValue,
+    ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
 };
+use serenity::model::prelude::interaction::InteractionResponseType;
+use serenity::prelude::Context;
+use std::{sync::Arc, time::Duration};
+use tracing::error;
 
-use crate::utils::openai::generate_images;
+use crate::utils::{
+    gallery::{record_upload, GalleryScope, UploadedImages},
+    image_options::{get_configured_models, get_edit_model, ImageOptions},
+    image_queue::{take_turn, ImageQueue, JobQueue, Ticket},
+    image_upload::{build_uploads, too_large_message},
+    moderation::{check_prompt, PromptSource},
+    openai::{create_variations, edit_images, generate_images, GeneratedImage},
+    outbound::{escape_mass_mentions, only_user},
+    rate_limit::{check_rate_limit, is_exempt, throttled_message, Feature},
+    redis_client::{RedisClient, RedisManager},
+    usage::{check_quota, try_record_usage, UsageRecord},
+};
+
+/// Largest source image or mask OpenAI accepts.
+const MAX_SOURCE_BYTES: u64 = 4 * 1024 * 1024;
 
-pub async fn run(options: &[CommandDataOption]) -> String {
-    let option = options
-        .get(0)
-        .expect("Expected a string")
+/// How long `user_id` has to wait before drawing again, if they are over the
+/// `/imagine` rate limit. Re-rolls from `/gallery` and images drawn in chat
+/// count towards it too. `roles` are theirs in the server, if any.
+pub async fn get_throttle(
+    conn: &mut RedisManager,
+    user_id: UserId,
+    roles: &[RoleId],
+    channel_id: ChannelId,
+) -> Option<Duration> {
+    if is_exempt(roles) {
+        return None;
+    }
+
+    check_rate_limit(conn, Feature::Imagine, user_id, channel_id)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to check rate limit: {}", e);
+            None
+        })
+}
+
+pub async fn run(ctx: Context, command: ApplicationCommandInteraction) {
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
+
+    // Answer before deferring, only the first response can be ephemeral
+    let throttle = get_throttle(
+        &mut conn,
+        command.user.id,
+        command
+            .member
+            .as_ref()
+            .map(|member| member.roles.as_slice())
+            .unwrap_or_default(),
+        command.channel_id,
+    )
+    .await;
+    if let Some(wait) = throttle {
+        if let Err(why) = command
+            .create_interaction_response(&ctx.http, |response| {
+                response
+                    .kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|message| {
+                        message.content(throttled_message(wait)).ephemeral(true)
+                    })
+            })
+            .await
+        {
+            error!("Cannot respond to slash command: {}", why);
+        }
+        return;
+    }
+
+    command.defer(&ctx).await.unwrap();
+    let queue = {
+        let data = ctx.data.read().await;
+        data.get::<ImageQueue>().unwrap().clone()
+    };
+    let mut ticket = None;
+    let drawing = imagine(&ctx, &mut conn, &command, &queue, &mut ticket).await;
+    let (content, uploads) = match &drawing {
+        Ok(drawing) => {
+            let settings = drawing.options.describe();
+            let (uploads, too_large) = build_uploads(&drawing.images, &drawing.prompt, &settings);
+            let content = match too_large_message(&too_large) {
+                Some(message) => format!("{}\n{}", settings, message),
+                None => settings,
+            };
+            (content, uploads)
+        }
+        Err(message) => (message.clone(), vec![]),
+    };
+    if let Err(why) = command
+        .edit_original_interaction_response(&ctx.http, |response| {
+            response
+                .content(escape_mass_mentions(content))
+                .allowed_mentions(|am| only_user(am, command.user.id))
+        })
+        .await
+    {
+        error!("Cannot respond to slash command: {}", why);
+    }
+    // Responses can't be edited to carry files, so the images follow up
+    let Ok(drawing) = drawing else {
+        return;
+    };
+    let scope = GalleryScope::new(command.guild_id, command.channel_id);
+    let upload = UploadedImages {
+        prompt: &drawing.prompt,
+        images: &drawing.images,
+        author_id: command.user.id,
+        options: drawing.is_rerollable.then_some(&drawing.options),
+    };
+    for (files, embeds) in uploads {
+        let result = command
+            .create_followup_message(&ctx.http, |followup| {
+                followup.add_files(files).add_embeds(embeds)
+            })
+            .await;
+        match result {
+            Ok(message) => {
+                if let Err(e) = record_upload(&mut conn, scope, &message, &upload).await {
+                    error!("Failed to record images in the gallery: {}", e);
+                }
+            }
+            Err(why) => error!("Cannot upload images: {}", why),
+        }
+    }
+}
+
+struct Drawing {
+    prompt: String,
+    options: ImageOptions,
+    images: Vec<GeneratedImage>,
+    /// Drawn from the prompt alone, so drawing it again needs nothing else.
+    is_rerollable: bool,
+}
+
+fn get_option<'a>(
+    options: &'a [CommandDataOption],
+    name: &str,
+) -> Option<&'a CommandDataOptionValue> {
+    options
+        .iter()
+        .find(|option| option.name == name)?
         .resolved
         .as_ref()
-        .expect("Expected string");
+}
 
-    if let CommandDataOptionValue::String(prompt) = option {
-        let response = generate_images(prompt).await.map(|urls| urls.join("\n"));
-        match response {
-            Ok(response) => format!("{}\n ```{}```", response, prompt),
-            Err(e) => format!("OpenAI: {}", e),
-        }
+fn get_string(options: &[CommandDataOption], name: &str) -> Option<String> {
+    match get_option(options, name)? {
+        CommandDataOptionValue::String(value) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+fn get_attachment(options: &[CommandDataOption], name: &str) -> Option<Attachment> {
+    match get_option(options, name)? {
+        CommandDataOptionValue::Attachment(attachment) => Some(attachment.clone()),
+        _ => None,
+    }
+}
+
+/// Fetches an uploaded source image or mask, which OpenAI wants as a PNG.
+async fn download_png(attachment: &Attachment) -> Result<Vec<u8>, String> {
+    if attachment.content_type.as_deref() != Some("image/png") {
+        return Err(format!("{} needs to be a PNG.", attachment.filename));
+    }
+    if attachment.size > MAX_SOURCE_BYTES {
+        return Err(format!(
+            "{} needs to be smaller than 4 MB.",
+            attachment.filename
+        ));
+    }
+    attachment.download().await.map_err(|e| {
+        error!("Failed to download {}: {}", attachment.url, e);
+        format!("Couldn't download {}.", attachment.filename)
+    })
+}
+
+fn get_image_options(options: &[CommandDataOption], is_edit: bool) -> ImageOptions {
+    let defaults = ImageOptions::default();
+    let default_model = if is_edit {
+        get_edit_model().name.to_string()
     } else {
-        "Please provide a valid prompt".to_string()
+        defaults.model
+    };
+    ImageOptions {
+        model: get_string(options, "model").unwrap_or(default_model),
+        size: get_string(options, "size"),
+        quality: get_string(options, "quality"),
+        style: get_string(options, "style"),
+        n: match get_option(options, "n") {
+            Some(CommandDataOptionValue::Integer(n)) => (*n).clamp(0, u32::MAX.into()) as u32,
+            _ => defaults.n,
+        },
+    }
+}
+
+async fn imagine(
+    ctx: &Context,
+    conn: &mut RedisManager,
+    command: &ApplicationCommandInteraction,
+    queue: &Arc<JobQueue>,
+    ticket: &mut Option<Ticket>,
+) -> Result<Drawing, String> {
+    let prompt = get_string(&command.data.options, "prompt");
+    let image = get_attachment(&command.data.options, "image");
+    let mask = get_attachment(&command.data.options, "mask");
+    let options = get_image_options(&command.data.options, image.is_some());
+    match (&image, &mask, &prompt) {
+        (None, None, None) => return Err("Please provide a prompt or an image".to_string()),
+        (None, Some(_), _) | (_, Some(_), None) => {
+            return Err("A mask needs an image and a prompt to go with it.".to_string())
+        }
+        (Some(_), _, _) => options.validate_edit()?,
+        (None, None, Some(_)) => options.validate()?,
     }
+    if let Some(prompt) = &prompt {
+        let source = PromptSource {
+            user_id: command.user.id,
+            guild_id: command.guild_id,
+            channel_id: command.channel_id,
+            feature: Feature::Imagine,
+        };
+        check_prompt(conn, &source, prompt).await?;
+    }
+    check_quota(conn, command.user.id, command.guild_id).await?;
+
+    let is_rerollable = image.is_none();
+    let source = match &image {
+        Some(image) => Some(download_png(image).await?),
+        None => None,
+    };
+    let mask = match &mask {
+        Some(mask) => Some(download_png(mask).await?),
+        None => None,
+    };
+
+    let _permit = take_turn(queue, ticket, |content| {
+        command.edit_original_interaction_response(&ctx.http, |response| response.content(content))
+    })
+    .await
+    .map_err(|e| e.friendly_message().to_string())?;
+
+    let (prompt, result) = match (image, source, prompt) {
+        (Some(_), Some(source), Some(prompt)) => {
+            let result = edit_images(&prompt, &source, mask.as_deref(), &options).await;
+            (prompt, result)
+        }
+        (Some(image), Some(source), None) => (
+            format!("Variation of {}", image.filename),
+            create_variations(&source, &options).await,
+        ),
+        (None, _, Some(prompt)) => {
+            let result = generate_images(&prompt, &options).await;
+            (prompt, result)
+        }
+        _ => unreachable!("checked above"),
+    };
+    let images = result.map_err(|e| e.friendly_message().to_string())?;
+    let usage = UsageRecord::images(&options.model, images.len());
+    try_record_usage(conn, command.user.id, command.guild_id, &[usage]).await;
+    Ok(Drawing {
+        prompt,
+        options,
+        images,
+        is_rerollable,
+    })
 }
 
+/// Every value any of `values` offers, in order, without repeats.
+fn union<'a>(values: impl Iterator<Item = &'a [&'static str]>) -> Vec<&'static str> {
+    let mut union = Vec::new();
+    for value in values.flatten() {
+        if !union.contains(value) {
+            union.push(*value);
+        }
+    }
+    union
+}
+
+fn add_choices<'a>(
+    option: &'a mut CreateApplicationCommandOption,
+    choices: &[&str],
+) -> &'a mut CreateApplicationCommandOption {
+    for choice in choices {
+        option.add_string_choice(choice, choice);
+    }
+    option
+}
+
+/// Offers the settings of the models in `IMAGE_MODELS`. Choices only one
+/// of them supports are checked against the chosen model when drawing.
 pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    let models = get_configured_models();
     command
         .name("imagine")
         .description("Ask ai to draw")
         .create_option(|option| {
             option
                 .name("prompt")
-                .description("The instruction")
+                .description("The instruction, leave it out to get variations of an image")
                 .kind(CommandOptionType::String)
-                .required(true)
         })
+        .create_option(|option| {
+            option
+                .name("image")
+                .description("PNG to edit as the prompt says, or to draw variations of")
+                .kind(CommandOptionType::Attachment)
+        })
+        .create_option(|option| {
+            option
+                .name("mask")
+                .description("PNG whose transparent areas mark what to edit in the image")
+                .kind(CommandOptionType::Attachment)
+        });
+
+    if models.len() > 1 {
+        command.create_option(|option| {
+            let names: Vec<_> = models.iter().map(|model| model.name).collect();
+            add_choices(
+                option
+                    .name("model")
+                    .description("Which model draws, the first one by default")
+                    .kind(CommandOptionType::String),
+                &names,
+            )
+        });
+    }
+    for (name, description, choices) in [
+        (
+            "size",
+            "Width x height in pixels",
+            union(models.iter().map(|model| model.sizes)),
+        ),
+        (
+            "quality",
+            "More detail takes longer",
+            union(models.iter().map(|model| model.qualities)),
+        ),
+        (
+            "style",
+            "Vivid is more dramatic, natural more realistic",
+            union(models.iter().map(|model| model.styles)),
+        ),
+    ] {
+        if choices.is_empty() {
+            continue;
+        }
+        command.create_option(|option| {
+            add_choices(
+                option
+                    .name(name)
+                    .description(description)
+                    .kind(CommandOptionType::String),
+                &choices,
+            )
+        });
+    }
+
+    let max_images = models
+        .iter()
+        .map(|model| model.max_images)
+        .max()
+        .unwrap_or(1);
+    if max_images > 1 {
+        command.create_option(|option| {
+            option
+                .name("n")
+                .description("How many images to draw")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .max_int_value(max_images)
+        });
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_union() {
+        let sizes = union(
+            [
+                ["1024x1024", "1792x1024"].as_slice(),
+                ["1024x1024", "512x512"].as_slice(),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(sizes, vec!["1024x1024", "1792x1024", "512x512"]);
+    }
 }
@@ -0,0 +1,259 @@
+use serenity::{
+    builder::CreateApplicationCommand,
+    model::{
+        application::interaction::{
+            application_command::{
+                ApplicationCommandInteraction, CommandDataOption, CommandDataOptionValue,
+            },
+            InteractionResponseType,
+        },
+        id::{ChannelId, GuildId},
+        prelude::command::CommandOptionType,
+        Permissions,
+    },
+    prelude::Context,
+};
+use tracing::error;
+
+use crate::utils::{
+    outbound::{escape_mass_mentions, only_user},
+    persona::{get_persona, get_preset, reset_persona, set_persona, PersonaScope, PRESETS},
+    redis_client::{RedisClient, RedisManager},
+};
+
+/// Longest custom prompt accepted, so `/persona show` fits in one message.
+const MAX_PROMPT_LENGTH: u16 = 1500;
+
+fn get_option<'a>(
+    options: &'a [CommandDataOption],
+    name: &str,
+) -> Option<&'a CommandDataOptionValue> {
+    options
+        .iter()
+        .find(|option| option.name == name)?
+        .resolved
+        .as_ref()
+}
+
+fn get_string<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a str> {
+    match get_option(options, name)? {
+        CommandDataOptionValue::String(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn get_bool(options: &[CommandDataOption], name: &str) -> bool {
+    matches!(
+        get_option(options, name),
+        Some(CommandDataOptionValue::Boolean(true))
+    )
+}
+
+fn get_scope(
+    options: &[CommandDataOption],
+    guild_id: GuildId,
+    channel_id: ChannelId,
+) -> PersonaScope {
+    if get_bool(options, "channel") {
+        PersonaScope::Channel(guild_id, channel_id)
+    } else {
+        PersonaScope::Guild(guild_id)
+    }
+}
+
+fn describe_scope(scope: PersonaScope) -> String {
+    match scope {
+        PersonaScope::Guild(_) => "this server".to_string(),
+        PersonaScope::Channel(_, channel_id) => format!("<#{}>", channel_id),
+    }
+}
+
+/// The prompt to store for `/persona set`, from either a preset or a custom
+/// prompt.
+fn resolve_prompt(preset: Option<&str>, prompt: Option<&str>) -> Result<String, String> {
+    match (preset, prompt.map(str::trim)) {
+        (Some(_), Some(_)) => Err("Pick either a preset or a prompt, not both.".to_string()),
+        (Some(preset), None) => get_preset(preset)
+            .map(str::to_string)
+            .ok_or_else(|| format!("There is no preset called `{}`.", preset)),
+        (None, Some(prompt)) if !prompt.is_empty() => Ok(prompt.to_string()),
+        _ => Err("Give a preset or a prompt.".to_string()),
+    }
+}
+
+fn format_presets() -> String {
+    PRESETS
+        .iter()
+        .map(|(name, prompt)| format!("**{}**: {}", name, prompt))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn handle(
+    conn: &mut RedisManager,
+    subcommand: &CommandDataOption,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+) -> String {
+    let options = &subcommand.options;
+    match subcommand.name.as_str() {
+        "set" => {
+            let prompt = match resolve_prompt(
+                get_string(options, "preset"),
+                get_string(options, "prompt"),
+            ) {
+                Ok(prompt) => prompt,
+                Err(e) => return e,
+            };
+            let scope = get_scope(options, guild_id, channel_id);
+            match set_persona(conn, scope, &prompt).await {
+                Ok(_) => format!("Persona set for {}.", describe_scope(scope)),
+                Err(e) => {
+                    error!("Failed to set persona: {}", e);
+                    "Failed to save the persona, please try again.".to_string()
+                }
+            }
+        }
+        "reset" => {
+            let scope = get_scope(options, guild_id, channel_id);
+            match reset_persona(conn, scope).await {
+                Ok(_) => format!("Persona reset for {}.", describe_scope(scope)),
+                Err(e) => {
+                    error!("Failed to reset persona: {}", e);
+                    "Failed to reset the persona, please try again.".to_string()
+                }
+            }
+        }
+        "show" => match get_persona(conn, Some(guild_id), channel_id).await {
+            Ok(Some((scope, prompt))) => {
+                format!("Persona for {}:\n>>> {}", describe_scope(scope), prompt)
+            }
+            Ok(None) => "No persona is set here, the default one is used.".to_string(),
+            Err(e) => {
+                error!("Failed to load persona: {}", e);
+                "Failed to load the persona, please try again.".to_string()
+            }
+        },
+        "list" => format_presets(),
+        _ => "Unknown subcommand".to_string(),
+    }
+}
+
+pub async fn run(ctx: Context, command: ApplicationCommandInteraction) {
+    let is_admin = command
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.administrator());
+
+    let content = match (command.guild_id, command.data.options.first()) {
+        (Some(_), _) if !is_admin => "Only admins can manage personas.".to_string(),
+        (Some(guild_id), Some(subcommand)) => {
+            let mut conn = {
+                let data = ctx.data.read().await;
+                data.get::<RedisClient>().unwrap().clone()
+            };
+            handle(&mut conn, subcommand, guild_id, command.channel_id).await
+        }
+        _ => "Personas can only be managed in a server.".to_string(),
+    };
+
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    // Prompts are user supplied, keep them from pinging anyone
+                    message
+                        .content(escape_mass_mentions(content))
+                        .allowed_mentions(|am| only_user(am, command.user.id))
+                        .ephemeral(true)
+                })
+        })
+        .await
+    {
+        error!("Cannot respond to slash command: {}", why);
+    }
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("persona")
+        .description("Manage the bot's personality")
+        .default_member_permissions(Permissions::ADMINISTRATOR)
+        .dm_permission(false)
+        .create_option(|option| {
+            option
+                .name("set")
+                .description("Set the persona from a preset or your own prompt")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|option| {
+                    option
+                        .name("preset")
+                        .description("A built-in persona")
+                        .kind(CommandOptionType::String);
+                    for (name, _) in PRESETS {
+                        option.add_string_choice(name, name);
+                    }
+                    option
+                })
+                .create_sub_option(|option| {
+                    option
+                        .name("prompt")
+                        .description("A custom system prompt")
+                        .kind(CommandOptionType::String)
+                        .max_length(MAX_PROMPT_LENGTH)
+                })
+                .create_sub_option(|option| {
+                    option
+                        .name("channel")
+                        .description("Only for this channel instead of the whole server")
+                        .kind(CommandOptionType::Boolean)
+                })
+        })
+        .create_option(|option| {
+            option
+                .name("show")
+                .description("Show the persona used in this channel")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|option| {
+            option
+                .name("reset")
+                .description("Go back to the default persona")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|option| {
+                    option
+                        .name("channel")
+                        .description("Only reset this channel's persona")
+                        .kind(CommandOptionType::Boolean)
+                })
+        })
+        .create_option(|option| {
+            option
+                .name("list")
+                .description("List the built-in personas")
+                .kind(CommandOptionType::SubCommand)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_prompt() {
+        assert_eq!(
+            resolve_prompt(Some("concise"), None).unwrap(),
+            get_preset("concise").unwrap()
+        );
+        assert_eq!(
+            resolve_prompt(None, Some("  Talk like a pirate ")).unwrap(),
+            "Talk like a pirate"
+        );
+        assert!(resolve_prompt(Some("pirate"), None).is_err());
+        assert!(resolve_prompt(Some("concise"), Some("pirate")).is_err());
+        assert!(resolve_prompt(None, Some(" ")).is_err());
+        assert!(resolve_prompt(None, None).is_err());
+    }
+}
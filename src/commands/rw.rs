@@ -1,46 +1,42 @@
-use redis::Commands;
+use redis::AsyncCommands;
 use serenity::framework::standard::macros::command;
 use serenity::framework::standard::{Args, CommandResult};
 use serenity::model::prelude::*;
 use serenity::prelude::*;
 
-use crate::utils::redis_client::RedisClient;
+use crate::utils::{outbound::reply_safely, redis_client::RedisClient};
 
 #[command]
 pub async fn write(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let data = ctx.data.write().await;
-    let client = data.get::<RedisClient>().unwrap();
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
     let key = args.single::<String>()?;
     let message = args.single::<String>()?;
-    let mut conn = client.get_connection().unwrap();
 
-    async {
-        match conn.set::<String, String, String>(key, message) {
-            Ok(_) => msg.reply(&ctx.http, "OK").await,
-            Err(_) => msg.reply(&ctx.http, "ERR").await,
-        }
-        .expect("failed to send message");
+    match conn.set::<String, String, ()>(key, message).await {
+        Ok(_) => msg.reply(&ctx.http, "OK").await,
+        Err(_) => msg.reply(&ctx.http, "ERR").await,
     }
-    .await;
+    .expect("failed to send message");
 
     Ok(())
 }
 
 #[command]
 pub async fn read(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
-    let data = ctx.data.write().await;
-    let client = data.get::<RedisClient>().unwrap();
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
     let key = args.single::<String>()?;
-    let mut conn = client.get_connection().unwrap();
 
-    async {
-        match conn.get::<String, String>(key) {
-            Ok(result) => msg.reply(&ctx.http, format!("Result: {:?}", result)).await,
-            Err(e) => msg.reply(&ctx.http, format!("Error: {:?}", e)).await,
-        }
-        .expect("failed to send message");
+    match conn.get::<String, String>(key).await {
+        Ok(result) => reply_safely(ctx, msg, format!("Result: {:?}", result)).await,
+        Err(e) => msg.reply(&ctx.http, format!("Error: {:?}", e)).await,
     }
-    .await;
+    .expect("failed to send message");
 
     Ok(())
 }
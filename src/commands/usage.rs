@@ -0,0 +1,133 @@
+use serenity::{
+    builder::CreateApplicationCommand,
+    model::application::interaction::{
+        application_command::{ApplicationCommandInteraction, CommandDataOptionValue},
+        InteractionResponseType,
+    },
+    model::prelude::command::CommandOptionType,
+    prelude::Context,
+};
+use tracing::error;
+
+use crate::utils::{
+    redis_client::RedisClient,
+    usage::{get_usage, UsageRecord, UsageScope},
+};
+
+const MAX_DAYS: u64 = 30;
+
+fn format_record(record: &UsageRecord) -> String {
+    let mut line = format!("**{}**:", record.model);
+    if record.tokens() > 0 {
+        line.push_str(&format!(
+            " {} prompt ({} cached) + {} completion tokens",
+            record.prompt_tokens, record.cached_tokens, record.completion_tokens
+        ));
+    }
+    if record.images > 0 {
+        line.push_str(&format!(" {} images", record.images));
+    }
+    if let Some(cost) = record.cost() {
+        line.push_str(&format!(", ~${:.4}", cost));
+    }
+    line
+}
+
+fn format_usage(title: &str, records: &[UsageRecord]) -> String {
+    if records.is_empty() {
+        return format!("{}: nothing yet", title);
+    }
+
+    let tokens: u64 = records.iter().map(UsageRecord::tokens).sum();
+    let cost: f64 = records.iter().filter_map(UsageRecord::cost).sum();
+    let mut lines = vec![format!("{}: {} tokens, ~${:.4}", title, tokens, cost)];
+    lines.extend(records.iter().map(format_record));
+    lines.join("\n")
+}
+
+pub async fn run(ctx: Context, command: ApplicationCommandInteraction) {
+    let days = match command
+        .data
+        .options
+        .first()
+        .and_then(|option| option.resolved.as_ref())
+    {
+        Some(CommandDataOptionValue::Integer(days)) => (*days as u64).clamp(1, MAX_DAYS),
+        _ => 1,
+    };
+
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
+    let mut scopes = vec![("You", UsageScope::User(command.user.id))];
+    scopes.extend(
+        command
+            .guild_id
+            .map(|guild_id| ("This server", UsageScope::Guild(guild_id))),
+    );
+
+    let mut sections = vec![format!("Usage over the last {} day(s)", days)];
+    for (title, scope) in scopes {
+        match get_usage(&mut conn, scope, days).await {
+            Ok(records) => sections.push(format_usage(title, &records)),
+            Err(e) => {
+                error!("Failed to load usage: {}", e);
+                sections.push(format!("{}: failed to load", title));
+            }
+        }
+    }
+
+    if let Err(why) = command
+        .create_interaction_response(&ctx.http, |response| {
+            response
+                .kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|message| {
+                    message.content(sections.join("\n\n")).ephemeral(true)
+                })
+        })
+        .await
+    {
+        error!("Cannot respond to slash command: {}", why);
+    }
+}
+
+pub fn register(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    command
+        .name("usage")
+        .description("Show how many tokens you and this server have used")
+        .create_option(|option| {
+            option
+                .name("days")
+                .description("How many days back to count, today included")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .max_int_value(MAX_DAYS)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_usage() {
+        let records = vec![
+            UsageRecord {
+                model: "gpt-4o".to_string(),
+                prompt_tokens: 1000,
+                cached_tokens: 0,
+                completion_tokens: 200,
+                images: 0,
+            },
+            UsageRecord::images("dall-e-3", 2),
+        ];
+
+        let formatted = format_usage("You", &records);
+        assert!(formatted.starts_with("You: 1200 tokens"));
+        assert!(formatted.contains("**gpt-4o**: 1000 prompt (0 cached) + 200 completion tokens"));
+        assert!(formatted.contains("**dall-e-3**: 2 images, ~$0.0800"));
+
+        assert_eq!(format_usage("You", &[]), "You: nothing yet");
+    }
+}
@@ -1,3 +1,5 @@
 pub mod chat;
 pub mod ming;
+// Disabled in `Handler::message` for now, kept around for when it is needed again.
+#[allow(dead_code)]
 pub mod twitter;
@@ -1,102 +1,722 @@
-use crate::utils::{openai::*, redis_client::RedisClient};
-use redis::{Commands, RedisResult};
+use crate::utils::{
+    bot_user::BotUser,
+    chat_store::{get_ttl, index_thread, refresh_thread, set_last_thread, summary_key, track_keys},
+    chat_turn::{parse_turn, ChatTurn, TurnRole},
+    chunker::{split_message, DISCORD_MESSAGE_LIMIT},
+    mentions::normalize_turns,
+    moderation::{check_prompt, PromptSource},
+    openai::{self, *},
+    outbound::{edit_safely, escape_mass_mentions, reply_safely},
+    persona::get_persona,
+    rate_limit::{check_rate_limit, is_exempt, should_notify_throttle, throttled_message, Feature},
+    redis_client::{RedisClient, RedisManager},
+    usage::{check_quota, try_record_usage},
+};
+use redis::{AsyncCommands, RedisResult};
 use serenity::{
-    model::{prelude::Message, user::User},
+    model::{
+        channel::AttachmentType,
+        id::{ChannelId, MessageId, UserId},
+        prelude::Message,
+        user::User,
+    },
     prelude::Context,
 };
-use tracing::{debug, error};
+use std::{collections::HashMap, env, time::Duration};
+use tokio::{
+    sync::mpsc,
+    time::{Interval, MissedTickBehavior},
+};
+use tracing::{debug, error, warn};
 
-pub const BOT_ID: u64 = 1042057406525485096;
+const STREAM_PLACEHOLDER: &str = "...";
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(750);
+/// Answers longer than this many characters are uploaded as a file instead
+/// of being split, unless `CHAT_ATTACHMENT_THRESHOLD` says otherwise.
+const DEFAULT_ATTACHMENT_THRESHOLD: usize = 3 * DISCORD_MESSAGE_LIMIT;
 
-fn handle_new_message(
-    conn: &mut redis::Connection,
-    message: &Message,
-) -> RedisResult<Vec<Message>> {
-    let key = format!("chat_{}", message.id);
-    let value = serde_json::to_string::<Message>(message).unwrap();
-    conn.lpush::<String, String, ()>(key.clone(), value)?;
+/// Rolling summary of the turns at the start of a thread that no longer fit
+/// the model's context window.
+#[derive(Debug, Default)]
+struct ThreadSummary {
+    text: Option<String>,
+    /// How many of the thread's oldest turns `text` covers.
+    turns: usize,
+}
+
+async fn handle_new_message(
+    conn: &mut RedisManager,
+    turn: &ChatTurn,
+    channel_id: ChannelId,
+) -> RedisResult<(String, Vec<ChatTurn>)> {
+    let key = format!("chat_{}", turn.message_id);
+    let value = serde_json::to_string::<ChatTurn>(turn).unwrap();
+    conn.lpush::<String, String, ()>(key.clone(), value).await?;
     debug!("created new history: {}", key);
 
-    let msg_key = format!("msg_{}", message.id);
-    conn.set::<String, String, ()>(msg_key, key)?;
-    Ok(vec![])
+    let msg_key = format!("msg_{}", turn.message_id);
+    conn.set::<&str, &str, ()>(&msg_key, &key).await?;
+    track_keys(conn, &key, &[msg_key]).await?;
+    index_thread(conn, &key, turn.author_id, channel_id).await?;
+    // Expire even if the answer never makes it to the end of the turn
+    refresh_thread(conn, &key).await?;
+    Ok((key, vec![]))
 }
 
-fn handle_reply_message(
-    conn: &mut redis::Connection,
-    message: &Message,
-) -> RedisResult<Vec<Message>> {
-    let referenced_message = message.referenced_message.as_ref().unwrap();
-    let pointer_key = format!("msg_{}", referenced_message.id);
-    let chat_history_key: String = match conn.get(&pointer_key) {
+/// Reads the turns of a thread. Entries still stored as serenity messages
+/// are rewritten as [`ChatTurn`]s on the way, entries that can't be read at
+/// all are left out.
+async fn load_history(
+    conn: &mut RedisManager,
+    chat_history_key: &str,
+    bot_id: UserId,
+) -> RedisResult<Vec<ChatTurn>> {
+    let values: Vec<String> = conn.lrange(chat_history_key, 0, -1).await?;
+    let mut history = Vec::with_capacity(values.len());
+    for (index, value) in values.iter().enumerate() {
+        match parse_turn(value, bot_id) {
+            Ok((turn, is_legacy)) => {
+                if is_legacy {
+                    let value = serde_json::to_string(&turn).unwrap();
+                    if let Err(e) = conn
+                        .lset::<&str, String, ()>(chat_history_key, index as isize, value)
+                        .await
+                    {
+                        warn!(
+                            "Failed to migrate turn {} of {}: {}",
+                            index, chat_history_key, e
+                        );
+                    }
+                }
+                history.push(turn);
+            }
+            Err(e) => warn!(
+                "Skipping unreadable turn {} of {}: {}",
+                index, chat_history_key, e
+            ),
+        }
+    }
+    Ok(history)
+}
+
+/// Where a reply goes, given the history of the thread it replies into.
+#[derive(Debug, PartialEq)]
+enum ReplyPlan {
+    /// The history is gone, so the reply starts a thread of its own.
+    New,
+    /// The reply follows the newest turn and is appended to the thread.
+    Continue,
+    /// The reply is to an earlier turn, so it starts a branch made of the
+    /// first `usize` turns of the thread.
+    Fork(usize),
+}
+
+fn plan_reply(history: &[ChatTurn], referenced_id: MessageId) -> ReplyPlan {
+    if history.is_empty() {
+        return ReplyPlan::New;
+    }
+    // Follow-up parts of a long answer belong to the turn of the answer
+    match history.iter().position(|turn| {
+        turn.message_id == referenced_id || turn.follow_up_ids.contains(&referenced_id)
+    }) {
+        Some(index) if index + 1 < history.len() => ReplyPlan::Fork(index + 1),
+        _ => ReplyPlan::Continue,
+    }
+}
+
+/// Starts a thread for `turn` that copies the first turns of another one in
+/// `history`, along with its summary if it doesn't reach past them.
+async fn fork_thread(
+    conn: &mut RedisManager,
+    chat_history_key: &str,
+    history: Vec<ChatTurn>,
+    turn: &ChatTurn,
+    channel_id: ChannelId,
+) -> RedisResult<(String, Vec<ChatTurn>)> {
+    let key = format!("chat_{}", turn.message_id);
+    let values: Vec<String> = history
+        .iter()
+        .chain([turn])
+        .map(|turn| serde_json::to_string::<ChatTurn>(turn).unwrap())
+        .collect();
+    conn.rpush::<&str, Vec<String>, ()>(&key, values).await?;
+    debug!("forked {} into {}", chat_history_key, key);
+
+    let summary = get_summary(conn, chat_history_key).await;
+    if summary.text.is_some() && summary.turns <= history.len() {
+        set_summary(conn, &key, &summary).await?;
+    }
+
+    let msg_key = format!("msg_{}", turn.message_id);
+    conn.set::<&str, &str, ()>(&msg_key, &key).await?;
+    track_keys(conn, &key, &[msg_key]).await?;
+    index_thread(conn, &key, turn.author_id, channel_id).await?;
+    refresh_thread(conn, &key).await?;
+    Ok((key, history))
+}
+
+async fn handle_reply_message(
+    conn: &mut RedisManager,
+    turn: &ChatTurn,
+    referenced_id: MessageId,
+    channel_id: ChannelId,
+    bot_id: UserId,
+) -> RedisResult<(String, Vec<ChatTurn>)> {
+    let pointer_key = format!("msg_{}", referenced_id);
+    let chat_history_key: String = match conn.get(&pointer_key).await {
         Ok(value) => value,
         Err(_) => {
-            return handle_new_message(conn, message);
+            return handle_new_message(conn, turn, channel_id).await;
         }
     };
 
-    let history_raw = conn.lrange::<String, Vec<String>>(chat_history_key.clone(), 0, -1);
-    let history: Vec<Message> = match history_raw {
-        Ok(history_raw) => history_raw
-            .iter()
-            .map(|message| serde_json::from_str::<Message>(message).unwrap())
-            .collect(),
+    let mut history = match load_history(conn, &chat_history_key, bot_id).await {
+        Ok(history) => history,
         Err(_) => {
-            return handle_new_message(conn, message);
+            return handle_new_message(conn, turn, channel_id).await;
         }
     };
 
-    let value = serde_json::to_string::<Message>(message).unwrap();
-    conn.rpush::<String, String, ()>(chat_history_key.clone(), value)?;
+    match plan_reply(&history, referenced_id) {
+        ReplyPlan::New => return handle_new_message(conn, turn, channel_id).await,
+        ReplyPlan::Fork(turns) => {
+            history.truncate(turns);
+            return fork_thread(conn, &chat_history_key, history, turn, channel_id).await;
+        }
+        ReplyPlan::Continue => {}
+    }
+
+    let value = serde_json::to_string::<ChatTurn>(turn).unwrap();
+    conn.rpush::<String, String, ()>(chat_history_key.clone(), value)
+        .await?;
     debug!("continued at history: {}", chat_history_key);
 
-    let msg_key = format!("msg_{}", message.id);
-    conn.set::<String, String, ()>(msg_key, chat_history_key)?;
+    let msg_key = format!("msg_{}", turn.message_id);
+    conn.set::<&str, &str, ()>(&msg_key, &chat_history_key)
+        .await?;
+    track_keys(conn, &chat_history_key, &[msg_key]).await?;
 
     // Return as a RedisResult
-    Ok(history)
+    Ok((chat_history_key, history))
 }
 
-fn process_message(conn: &mut redis::Connection, message: Message) -> RedisResult<Vec<Message>> {
-    match message.referenced_message {
-        Some(_) => handle_reply_message(conn, &message),
-        None => handle_new_message(conn, &message),
+async fn process_message(
+    conn: &mut RedisManager,
+    message: &Message,
+    bot_id: UserId,
+) -> RedisResult<(String, Vec<ChatTurn>)> {
+    let turn = ChatTurn::from_message(message, bot_id);
+    let (chat_history_key, history) = match &message.referenced_message {
+        Some(referenced_message) => {
+            handle_reply_message(
+                conn,
+                &turn,
+                referenced_message.id,
+                message.channel_id,
+                bot_id,
+            )
+            .await?
+        }
+        None => handle_new_message(conn, &turn, message.channel_id).await?,
+    };
+    set_last_thread(
+        conn,
+        &chat_history_key,
+        message.author.id,
+        message.channel_id,
+    )
+    .await?;
+    Ok((chat_history_key, history))
+}
+
+fn get_attachment_threshold() -> usize {
+    env::var("CHAT_ATTACHMENT_THRESHOLD")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_ATTACHMENT_THRESHOLD)
+}
+
+fn is_summary_enabled() -> bool {
+    env::var("HISTORY_SUMMARY").is_ok_and(|value| value == "1" || value == "true")
+}
+
+async fn get_summary(conn: &mut RedisManager, chat_history_key: &str) -> ThreadSummary {
+    let summary_key = summary_key(chat_history_key);
+    let (text, turns): (Option<String>, Option<usize>) = conn
+        .hget(&summary_key, &["text", "turns"])
+        .await
+        .unwrap_or_default();
+    ThreadSummary {
+        text,
+        turns: turns.unwrap_or_default(),
+    }
+}
+
+async fn set_summary(
+    conn: &mut RedisManager,
+    chat_history_key: &str,
+    summary: &ThreadSummary,
+) -> RedisResult<()> {
+    let summary_key = summary_key(chat_history_key);
+    let mut pipe = redis::pipe();
+    pipe.hset_multiple(
+        &summary_key,
+        &[
+            ("text", summary.text.clone().unwrap_or_default()),
+            ("turns", summary.turns.to_string()),
+        ],
+    )
+    .ignore();
+    // Expires with the thread even if it isn't refreshed after this
+    if let Some(ttl) = get_ttl() {
+        pipe.expire(&summary_key, ttl).ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+/// Drops the oldest turns of `history` until the request fits the model's
+/// token budget. With `HISTORY_SUMMARY` enabled the dropped turns are folded
+/// into the thread's rolling summary, which is returned alongside.
+async fn fit_history(
+    conn: &mut RedisManager,
+    chat_history_key: &str,
+    new_message: &Message,
+    mut history: Vec<ChatTurn>,
+    tool_messages: &HashMap<MessageId, Vec<openai::Message>>,
+    persona: Option<&str>,
+    bot_id: UserId,
+) -> (Vec<ChatTurn>, Option<String>) {
+    let mut summary = if is_summary_enabled() {
+        get_summary(conn, chat_history_key).await
+    } else {
+        ThreadSummary::default()
+    };
+
+    let covered = summary.turns.min(history.len());
+    let dropped = covered
+        + count_overflowing_turns(
+            &ChatTurn::from_message(new_message, bot_id),
+            &history[covered..],
+            tool_messages,
+            summary.text.as_deref(),
+            persona,
+        );
+    let kept = history.split_off(dropped);
+
+    if is_summary_enabled() && dropped > covered {
+        let turns = history.split_off(covered);
+        match summarise_history(summary.text.clone(), turns).await {
+            Ok((text, usage)) => {
+                try_record_usage(conn, new_message.author.id, new_message.guild_id, &[usage]).await;
+                summary = ThreadSummary {
+                    text: Some(text),
+                    turns: dropped,
+                };
+                if let Err(e) = set_summary(conn, chat_history_key, &summary).await {
+                    error!("Failed to store summary of {}: {}", chat_history_key, e);
+                }
+            }
+            Err(e) => warn!("Failed to summarise {}: {}", chat_history_key, e),
+        }
+    }
+    if dropped > 0 {
+        debug!("left out {} turns of {}", dropped, chat_history_key);
     }
+
+    (kept, summary.text)
 }
 
-fn is_tagging_me_only(mentions: &[User]) -> bool {
-    mentions
+/// Loads the tool calls and results stored for the bot's turns in `history`.
+async fn get_tool_messages(
+    conn: &mut RedisManager,
+    history: &[ChatTurn],
+) -> HashMap<MessageId, Vec<openai::Message>> {
+    let ids: Vec<MessageId> = history
         .iter()
-        .all(|mention| *mention.id.as_u64() == BOT_ID)
+        .filter(|turn| turn.role == TurnRole::Assistant)
+        .map(|turn| turn.message_id)
+        .collect();
+    if ids.is_empty() {
+        return HashMap::new();
+    }
+
+    let keys: Vec<String> = ids.iter().map(|id| format!("tools_{}", id)).collect();
+    // MGET answers with a plain value for a single key, so ask for a list
+    let values: Vec<Option<String>> = match redis::cmd("MGET").arg(&keys).query_async(conn).await {
+        Ok(values) => values,
+        Err(e) => {
+            error!("Failed to load tool messages: {}", e);
+            return HashMap::new();
+        }
+    };
+
+    ids.into_iter()
+        .zip(values)
+        .filter_map(|(id, value)| Some((id, serde_json::from_str(&value?).ok()?)))
+        .collect()
 }
 
+async fn set_tool_messages(
+    conn: &mut RedisManager,
+    chat_history_key: &str,
+    message_id: MessageId,
+    tool_messages: &[openai::Message],
+) -> RedisResult<()> {
+    let key = format!("tools_{}", message_id);
+    let value = serde_json::to_string(tool_messages).unwrap();
+    conn.set::<&str, String, ()>(&key, value).await?;
+    track_keys(conn, chat_history_key, &[key]).await
+}
+
+fn is_tagging_me_only(mentions: &[User], bot_id: UserId) -> bool {
+    mentions.iter().all(|mention| mention.id == bot_id)
+}
+
+/// Adds deltas to `content` until `interval` allows the next edit, and
+/// returns what to show then. `None` once the answer is complete.
+async fn next_preview(
+    receiver: &mut mpsc::UnboundedReceiver<String>,
+    content: &mut String,
+    interval: &mut Interval,
+) -> Option<String> {
+    let mut is_dirty = false;
+    loop {
+        tokio::select! {
+            delta = receiver.recv() => {
+                content.push_str(&delta?);
+                is_dirty = true;
+            }
+            _ = interval.tick(), if is_dirty => {
+                // Only the part being written fits, the rest is sent once done
+                return Some(
+                    split_message(content, DISCORD_MESSAGE_LIMIT)
+                        .pop()
+                        .unwrap_or_default(),
+                );
+            }
+        }
+    }
+}
+
+/// Edits `reply` with the accumulated answer while deltas keep arriving,
+/// at most once per [`STREAM_EDIT_INTERVAL`] to stay within rate limits.
+async fn stream_into_reply(
+    ctx: &Context,
+    reply: &mut Message,
+    user_id: UserId,
+    mut receiver: mpsc::UnboundedReceiver<String>,
+) {
+    let mut content = String::new();
+    let mut interval = tokio::time::interval(STREAM_EDIT_INTERVAL);
+    // Ticks missed while waiting for text would otherwise fire all at once
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    while let Some(preview) = next_preview(&mut receiver, &mut content, &mut interval).await {
+        if let Err(e) = edit_safely(ctx, reply, user_id, preview).await {
+            error!("Failed to edit streamed message: {}", e);
+        }
+    }
+}
+
+/// Puts `answer` into `reply`, continuing in follow-up replies when it is over
+/// Discord's length limit or as a `.md` file when it is very long. The full
+/// answer is stored as one turn, and every message sent for it points at the
+/// thread so replying to any of them continues the conversation.
 async fn send_response_and_update_history(
     ctx: &Context,
     message: &Message,
-    response: String,
-    conn: &mut redis::Connection,
+    answer: ChatAnswer,
+    mut reply: Message,
+    conn: &mut RedisManager,
+    bot_id: UserId,
 ) {
-    match message.reply(ctx, response).await {
-        Ok(bot_response) => {
-            handle_reply_message(conn, &bot_response).unwrap();
+    let mut follow_up_ids = Vec::new();
+    let result = if answer.content.chars().count() > get_attachment_threshold() {
+        let file = AttachmentType::Bytes {
+            data: answer.content.as_bytes().to_vec().into(),
+            filename: "reply.md".to_string(),
+        };
+        reply
+            .edit(ctx, |m| {
+                m.content("The answer is too long for a message, see the file.")
+                    .attachment(file)
+            })
+            .await
+    } else {
+        // Escaped before splitting so the parts still fit
+        let content = escape_mass_mentions(&answer.content);
+        let mut chunks = split_message(&content, DISCORD_MESSAGE_LIMIT).into_iter();
+        let first = chunks.next().unwrap_or_default();
+        let mut result = edit_safely(ctx, &mut reply, message.author.id, first).await;
+        for chunk in chunks {
+            if result.is_err() {
+                break;
+            }
+            result = reply_safely(ctx, message, chunk)
+                .await
+                .map(|follow_up| follow_up_ids.push(follow_up.id));
         }
-        Err(e) => {
+        result
+    };
+
+    if let Err(e) = result {
+        error!("Failed to send message: {}", e);
+        if let Err(e) = reply_safely(ctx, message, format!("Discord: {}", e)).await {
             error!("Failed to send message: {}", e);
-            message.reply(ctx, format!("Discord: {}", e)).await.unwrap();
+            return;
+        }
+        if follow_up_ids.is_empty() {
+            return;
+        }
+    }
+
+    let mut turn = ChatTurn::from_message(&reply, bot_id);
+    turn.content = answer.content;
+    turn.follow_up_ids = follow_up_ids.clone();
+    let result = handle_reply_message(conn, &turn, message.id, message.channel_id, bot_id).await;
+    let chat_history_key = match result {
+        Ok((chat_history_key, _)) => chat_history_key,
+        Err(e) => {
+            error!("Failed to store reply {}: {}", turn.message_id, e);
+            return;
+        }
+    };
+    for follow_up_id in follow_up_ids {
+        let msg_key = format!("msg_{}", follow_up_id);
+        if let Err(e) = conn
+            .set::<&str, &str, ()>(&msg_key, &chat_history_key)
+            .await
+        {
+            error!(
+                "Failed to link {} to {}: {}",
+                follow_up_id, chat_history_key, e
+            );
+        }
+        if let Err(e) = track_keys(conn, &chat_history_key, &[msg_key]).await {
+            error!("Failed to track {}: {}", follow_up_id, e);
+        }
+    }
+    if !answer.tool_messages.is_empty() {
+        if let Err(e) =
+            set_tool_messages(conn, &chat_history_key, reply.id, &answer.tool_messages).await
+        {
+            error!("Failed to store tool messages: {}", e);
         }
     }
+    if let Err(e) = refresh_thread(conn, &chat_history_key).await {
+        error!("Failed to refresh expiry of {}: {}", chat_history_key, e);
+    }
+}
+
+/// How long the author has to wait before chatting again, if they are over
+/// the chat rate limit.
+async fn get_throttle(conn: &mut RedisManager, message: &Message) -> Option<Duration> {
+    let roles = message
+        .member
+        .as_ref()
+        .map(|member| member.roles.as_slice())
+        .unwrap_or_default();
+    if is_exempt(roles) {
+        return None;
+    }
+
+    check_rate_limit(conn, Feature::Chat, message.author.id, message.channel_id)
+        .await
+        .unwrap_or_else(|e| {
+            error!("Failed to check rate limit: {}", e);
+            None
+        })
 }
 
 pub async fn chat_handler(ctx: &Context, new_message: &Message) {
-    if !new_message.mentions.is_empty() && is_tagging_me_only(&new_message.mentions) {
-        let data = ctx.data.write().await;
-        let client = data.get::<RedisClient>().unwrap();
-        let mut conn = client.get_connection().unwrap();
+    let (mut conn, bot_id) = {
+        let data = ctx.data.read().await;
+        let Some(&bot_id) = data.get::<BotUser>() else {
+            // Not ready yet, so we can't tell whether we are being tagged
+            return;
+        };
+        (data.get::<RedisClient>().unwrap().clone(), bot_id)
+    };
+
+    if !new_message.mentions.is_empty() && is_tagging_me_only(&new_message.mentions, bot_id) {
+        if let Some(wait) = get_throttle(&mut conn, new_message).await {
+            let should_notify =
+                should_notify_throttle(&mut conn, Feature::Chat, new_message.author.id, wait)
+                    .await
+                    .unwrap_or_else(|e| {
+                        error!("Failed to check throttle notice: {}", e);
+                        false
+                    });
+            let result = if should_notify {
+                reply_safely(ctx, new_message, throttled_message(wait))
+                    .await
+                    .map(|_| ())
+            } else {
+                new_message.react(ctx, '⏳').await.map(|_| ())
+            };
+            if let Err(e) = result {
+                error!("Failed to send message: {}", e);
+            }
+            return;
+        }
+        if let Err(message) =
+            check_quota(&mut conn, new_message.author.id, new_message.guild_id).await
+        {
+            if let Err(e) = new_message.reply(ctx, message).await {
+                error!("Failed to send message: {}", e);
+            }
+            return;
+        }
+
+        let source = PromptSource {
+            user_id: new_message.author.id,
+            guild_id: new_message.guild_id,
+            channel_id: new_message.channel_id,
+            feature: Feature::Chat,
+        };
+        if let Err(refusal) = check_prompt(&mut conn, &source, &new_message.content).await {
+            if let Err(e) = reply_safely(ctx, new_message, refusal).await {
+                error!("Failed to send message: {}", e);
+            }
+            return;
+        }
+
+        let (chat_history_key, mut history) =
+            match process_message(&mut conn, new_message, bot_id).await {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Failed to load chat history: {}", e);
+                    Default::default()
+                }
+            };
+        // Before fitting, so summaries and token counts see what the model will
+        normalize_turns(ctx, new_message.guild_id, bot_id, &mut history);
+        let persona =
+            match get_persona(&mut conn, new_message.guild_id, new_message.channel_id).await {
+                Ok(persona) => persona.map(|(_, prompt)| prompt),
+                Err(e) => {
+                    error!("Failed to load persona: {}", e);
+                    None
+                }
+            };
+        let tool_messages = get_tool_messages(&mut conn, &history).await;
+        let (history, summary) = fit_history(
+            &mut conn,
+            &chat_history_key,
+            new_message,
+            history,
+            &tool_messages,
+            persona.as_deref(),
+            bot_id,
+        )
+        .await;
+        let conversation = Conversation {
+            tool_messages,
+            history,
+            summary,
+            persona,
+            bot_id,
+        };
+
+        let mut reply = match new_message.reply(ctx, STREAM_PLACEHOLDER).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                error!("Failed to send message: {}", e);
+                return;
+            }
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (answer, _) = tokio::join!(
+            ask_chat_gpt(ctx, new_message, conversation, Some(sender)),
+            stream_into_reply(ctx, &mut reply, new_message.author.id, receiver),
+        );
+
+        try_record_usage(
+            &mut conn,
+            new_message.author.id,
+            new_message.guild_id,
+            &answer.usage,
+        )
+        .await;
+        send_response_and_update_history(ctx, new_message, answer, reply, &mut conn, bot_id).await;
+    }
+}
 
-        let content = new_message.content.clone();
-        let history = process_message(&mut conn, new_message.clone()).unwrap_or_default();
-        let response = ask_chat_gpt(content, history).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::chat_turn::CHAT_TURN_VERSION;
+    use tokio::time::Instant;
+
+    fn turn(id: u64) -> ChatTurn {
+        ChatTurn {
+            version: CHAT_TURN_VERSION,
+            message_id: MessageId(id),
+            author_id: UserId(2),
+            display_name: "alice".to_string(),
+            role: TurnRole::User,
+            content: "hi".to_string(),
+            attachments: vec![],
+            follow_up_ids: vec![],
+            timestamp: 1697587200,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_next_preview_after_stall() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut content = String::new();
+        let mut interval = tokio::time::interval(STREAM_EDIT_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        sender.send("a".to_string()).unwrap();
+        let preview = next_preview(&mut receiver, &mut content, &mut interval).await;
+        assert_eq!(preview.as_deref(), Some("a"));
+
+        // Nothing arrives for a while, then text comes in quick succession
+        tokio::time::advance(STREAM_EDIT_INTERVAL * 10).await;
+        let start = Instant::now();
+        sender.send("b".to_string()).unwrap();
+        let preview = next_preview(&mut receiver, &mut content, &mut interval).await;
+        assert_eq!(preview.as_deref(), Some("ab"));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        sender.send("c".to_string()).unwrap();
+        let preview = next_preview(&mut receiver, &mut content, &mut interval).await;
+        assert_eq!(preview.as_deref(), Some("abc"));
+        assert!(start.elapsed() >= STREAM_EDIT_INTERVAL);
+
+        drop(sender);
+        let preview = next_preview(&mut receiver, &mut content, &mut interval).await;
+        assert_eq!(preview, None);
+    }
+
+    #[test]
+    fn test_plan_reply_continues_from_newest_turn() {
+        let history = vec![turn(1), turn(2), turn(3)];
+        assert_eq!(plan_reply(&history, MessageId(3)), ReplyPlan::Continue);
+    }
+
+    #[test]
+    fn test_plan_reply_forks_from_earlier_turn() {
+        let history = vec![turn(1), turn(2), turn(3)];
+        assert_eq!(plan_reply(&history, MessageId(1)), ReplyPlan::Fork(1));
+        assert_eq!(plan_reply(&history, MessageId(2)), ReplyPlan::Fork(2));
+    }
+
+    #[test]
+    fn test_plan_reply_without_history() {
+        assert_eq!(plan_reply(&[], MessageId(1)), ReplyPlan::New);
+    }
 
-        send_response_and_update_history(ctx, new_message, response, &mut conn).await;
+    #[test]
+    fn test_plan_reply_to_follow_up() {
+        let mut answer = turn(2);
+        answer.follow_up_ids = vec![MessageId(5), MessageId(6)];
+        let mut newest = turn(4);
+        newest.follow_up_ids = vec![MessageId(7)];
+        let history = vec![turn(1), answer, turn(3), newest];
+        assert_eq!(plan_reply(&history, MessageId(6)), ReplyPlan::Fork(2));
+        assert_eq!(plan_reply(&history, MessageId(7)), ReplyPlan::Continue);
     }
 }
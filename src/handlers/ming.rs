@@ -1,9 +1,9 @@
-use crate::utils::redis_client::RedisClient;
+use crate::utils::redis_client::{RedisClient, RedisManager};
 use lazy_static::lazy_static;
-use redis::{Commands, Connection};
+use redis::AsyncCommands;
 use regex::Regex;
 use serenity::{model::prelude::Message, prelude::Context};
-use tracing::debug;
+use tracing::{debug, error};
 
 lazy_static! {
     static ref URL_RE: Regex =
@@ -12,7 +12,7 @@ lazy_static! {
 
 const TTL_SECONDS: usize = 60 * 60 * 24; // 1 day
 
-fn extract_urls(url: String) -> Option<Vec<String>> {
+pub fn extract_urls(url: String) -> Option<Vec<String>> {
     if !URL_RE.is_match(&url) {
         return None;
     }
@@ -25,20 +25,30 @@ fn extract_urls(url: String) -> Option<Vec<String>> {
     Some(urls)
 }
 
-fn store_message(conn: &mut Connection, key: String, message: &Message) {
+async fn store_message(conn: &mut RedisManager, key: String, message: &Message) {
     let value = format! {"https://discord.com/channels/{}/{}/{}", message.guild_id.unwrap(), message.channel_id, message.id};
-    conn.set_ex::<String, String, String>(key, value, TTL_SECONDS)
-        .expect("msg store failed");
+    if let Err(e) = conn
+        .set_ex::<String, String, ()>(key, value, TTL_SECONDS)
+        .await
+    {
+        error!("Failed to store message: {}", e);
+    }
+}
+
+/// Link to the message that first posted `url` within the last day.
+pub async fn get_original_post(conn: &mut RedisManager, url: &str) -> Option<String> {
+    conn.get::<&str, Option<String>>(url).await.ok().flatten()
 }
 
-fn url_exists(conn: &mut Connection, url: &str) -> bool {
-    conn.get::<&str, String>(url).is_ok()
+async fn url_exists(conn: &mut RedisManager, url: &str) -> bool {
+    get_original_post(conn, url).await.is_some()
 }
 
 pub async fn ming_handler(ctx: &Context, new_message: &Message) {
-    let data = ctx.data.write().await;
-    let client = data.get::<RedisClient>().unwrap();
-    let mut conn = client.get_connection().unwrap();
+    let mut conn = {
+        let data = ctx.data.read().await;
+        data.get::<RedisClient>().unwrap().clone()
+    };
     let content = new_message.content.clone();
 
     let mut is_ming = false;
@@ -46,12 +56,12 @@ pub async fn ming_handler(ctx: &Context, new_message: &Message) {
         for u in urls {
             debug!("URL found: {}", u);
 
-            if url_exists(&mut conn, &u) {
+            if url_exists(&mut conn, &u).await {
                 debug!("URL found in redis: {}", u);
                 is_ming = true;
             }
 
-            store_message(&mut conn, u, new_message);
+            store_message(&mut conn, u, new_message).await;
         }
     }
 
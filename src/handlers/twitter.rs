@@ -3,6 +3,8 @@ use regex::Regex;
 use serenity::{model::prelude::Message, prelude::Context};
 use tracing::debug;
 
+use crate::utils::outbound::reply_safely;
+
 lazy_static! {
     static ref URL_RE: Regex =
         Regex::new(r"https:\/\/(www.|)(twitter|x)\.com\/(#!\/)?(\w+)\/status(es)*\/(\d+)").unwrap();
@@ -25,9 +27,12 @@ pub async fn twitter_handler(ctx: &Context, new_message: &Message) {
 
     if is_twitter_url(&content) {
         debug!("Twitter URL detected");
-        let _ = new_message
-            .reply(&ctx.http, replace_twitter_url_with_vxtwitter(&content))
-            .await;
+        let _ = reply_safely(
+            ctx,
+            new_message,
+            replace_twitter_url_with_vxtwitter(&content),
+        )
+        .await;
     }
 }
 
@@ -41,7 +46,7 @@ mod tests {
         assert!(is_twitter_url(&test_url));
         assert!(
             replace_twitter_url_with_vxtwitter(&test_url)
-                == "https://vxtwitter.com/JonAiart/status/1714415995484622866?s=20".to_string()
+                == "https://vxtwitter.com/JonAiart/status/1714415995484622866?s=20"
         )
     }
 
@@ -51,7 +56,7 @@ mod tests {
         assert!(is_twitter_url(&test_url));
         assert!(
             replace_twitter_url_with_vxtwitter(&test_url)
-                == "https://vxtwitter.com/JonAiart/status/1714415995484622866?s=20".to_string()
+                == "https://vxtwitter.com/JonAiart/status/1714415995484622866?s=20"
         )
     }
 
@@ -62,13 +67,13 @@ mod tests {
         assert!(is_twitter_url(&test_url));
         assert!(
             replace_twitter_url_with_vxtwitter(&test_url)
-                == "https://vxtwitter.com/JonAiart/status/1714415995484622866?s=20".to_string()
+                == "https://vxtwitter.com/JonAiart/status/1714415995484622866?s=20"
         )
     }
 
     #[test]
     fn test_vxtwitter_url_fail() {
         let test_url = "https://vxtwitter.com/JonAiart/status/1714415995484622866?s=20".to_string();
-        assert!(is_twitter_url(&test_url) == false)
+        assert!(!is_twitter_url(&test_url))
     }
 }
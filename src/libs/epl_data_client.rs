@@ -1,7 +1,11 @@
 use reqwest::Error;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
-#[derive(Deserialize, Debug)]
+/// How long to wait for upstream before falling back to cached standings.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct TeamStanding {
     pub standing: usize,
@@ -16,7 +20,7 @@ pub struct TeamStanding {
     pub point_difference: i32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct StandingsResponse {
     pub standing: Vec<TeamStanding>,
@@ -25,7 +29,13 @@ pub struct StandingsResponse {
 
 pub async fn get_standings() -> Result<StandingsResponse, Error> {
     let url = "https://epl-discord-bot.kalvin.workers.dev/standing";
-    let response = reqwest::get(url).await?.json::<StandingsResponse>().await?;
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+    let response = client
+        .get(url)
+        .send()
+        .await?
+        .json::<StandingsResponse>()
+        .await?;
     Ok(response)
 }
 
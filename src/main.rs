@@ -1,8 +1,11 @@
 mod commands;
 mod handlers;
 mod libs;
+mod tools;
 mod utils;
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
 use dotenvy::dotenv;
 use serenity::async_trait;
@@ -21,6 +24,8 @@ use crate::commands::meta::*;
 use crate::commands::rw::*;
 use crate::handlers::chat::*;
 use crate::handlers::ming::*;
+use crate::utils::bot_user::*;
+use crate::utils::image_queue::*;
 use crate::utils::redis_client::*;
 
 struct Handler;
@@ -29,6 +34,10 @@ struct Handler;
 impl EventHandler for Handler {
     async fn ready(&self, ctx: Context, ready: Ready) {
         info!("Connected as {}", ready.user.name);
+        {
+            let mut data = ctx.data.write().await;
+            data.insert::<BotUser>(ready.user.id);
+        }
 
         let register_imagine_cmd_result =
             Command::create_global_application_command(&ctx.http, |command| {
@@ -46,6 +55,46 @@ impl EventHandler for Handler {
         if let Err(why) = register_epl_standing_cmd_result {
             error!("Cannot register slash command: {}", why);
         }
+        let register_persona_cmd_result =
+            Command::create_global_application_command(&ctx.http, |command| {
+                commands::persona::register(command)
+            })
+            .await;
+        if let Err(why) = register_persona_cmd_result {
+            error!("Cannot register slash command: {}", why);
+        }
+        let register_usage_cmd_result =
+            Command::create_global_application_command(&ctx.http, |command| {
+                commands::usage::register(command)
+            })
+            .await;
+        if let Err(why) = register_usage_cmd_result {
+            error!("Cannot register slash command: {}", why);
+        }
+        let register_gallery_cmd_result =
+            Command::create_global_application_command(&ctx.http, |command| {
+                commands::gallery::register(command)
+            })
+            .await;
+        if let Err(why) = register_gallery_cmd_result {
+            error!("Cannot register slash command: {}", why);
+        }
+        let register_blocklist_cmd_result =
+            Command::create_global_application_command(&ctx.http, |command| {
+                commands::blocklist::register(command)
+            })
+            .await;
+        if let Err(why) = register_blocklist_cmd_result {
+            error!("Cannot register slash command: {}", why);
+        }
+        let register_forget_cmd_result =
+            Command::create_global_application_command(&ctx.http, |command| {
+                commands::forget::register(command)
+            })
+            .await;
+        if let Err(why) = register_forget_cmd_result {
+            error!("Cannot register slash command: {}", why);
+        }
 
         info!(
             "global slash command: {:#?}",
@@ -64,25 +113,37 @@ impl EventHandler for Handler {
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::MessageComponent(component) = &interaction {
+            if !commands::gallery::handle_component(&ctx, component).await {
+                error!("Unknown component: {}", component.data.custom_id);
+            }
+            return;
+        }
         if let Interaction::ApplicationCommand(command) = interaction {
             println!("Received command interaction: {:#?}", command);
 
             match command.data.name.as_str() {
                 "imagine" => {
-                    command.defer(&ctx).await.unwrap();
-                    let content = commands::imagine::run(&command.data.options).await;
-                    if let Err(why) = command
-                        .edit_original_interaction_response(&ctx.http, |response| {
-                            response.content(content)
-                        })
-                        .await
-                    {
-                        error!("Cannot respond to slash command: {}", why);
-                    }
+                    commands::imagine::run(ctx, command).await;
                 }
                 "epl_standing" => {
                     commands::epl_standing::run(ctx, command).await;
                 }
+                "persona" => {
+                    commands::persona::run(ctx, command).await;
+                }
+                "usage" => {
+                    commands::usage::run(ctx, command).await;
+                }
+                "forget" => {
+                    commands::forget::run(ctx, command).await;
+                }
+                "gallery" => {
+                    commands::gallery::run(ctx, command).await;
+                }
+                "blocklist" => {
+                    commands::blocklist::run(ctx, command).await;
+                }
                 _ => {
                     command.create_interaction_response(&ctx.http, |response| {
                         response
@@ -108,12 +169,21 @@ async fn main() {
 
     let redis_url = env::var("REDIS_DSL").expect("REDIS_DSL must be set");
     let redis_client = redis::Client::open(redis_url).expect("Failed to connect to Redis");
+    let redis_manager = RedisManager::new(redis_client)
+        .await
+        .expect("Failed to connect to Redis");
+
+    let image_queue = Arc::new(JobQueue::from_env());
 
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
     let framework = StandardFramework::new()
         .configure(|c| c.prefix("~"))
         .group(&GENERAL_GROUP);
-    let intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    // Guilds and members fill the cache mentions are named from
+    let intents = GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::MESSAGE_CONTENT
+        | GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MEMBERS;
     let mut client = Client::builder(&token, intents)
         .framework(framework)
         .event_handler(Handler)
@@ -122,7 +192,8 @@ async fn main() {
 
     {
         let mut data = client.data.write().await;
-        data.insert::<RedisClient>(redis_client);
+        data.insert::<RedisClient>(redis_manager);
+        data.insert::<ImageQueue>(image_queue.clone());
     }
 
     let shard_manager = client.shard_manager.clone();
@@ -131,6 +202,9 @@ async fn main() {
         tokio::signal::ctrl_c()
             .await
             .expect("Could not register ctrl+c handler");
+        // Let queued drawings say they were cancelled before disconnecting
+        image_queue.close();
+        image_queue.wait_idle(Duration::from_secs(5)).await;
         shard_manager.lock().await.shutdown_all().await;
     });
 
@@ -0,0 +1,127 @@
+pub mod epl_standing;
+pub mod imagine;
+pub mod reminder;
+pub mod repost;
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use serenity::{async_trait, model::prelude::Message, prelude::Context};
+use std::{collections::HashMap, env};
+use tracing::{debug, warn};
+
+use crate::utils::openai::{FunctionDefinition, ToolCall, ToolDefinition};
+
+/// A bot capability the chat model may call while answering.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn definition(&self) -> FunctionDefinition;
+
+    /// Runs the tool for `message`, the chat message being answered. The
+    /// returned text is handed back to the model as the tool result.
+    async fn call(
+        &self,
+        ctx: &Context,
+        message: &Message,
+        arguments: Value,
+    ) -> Result<String, String>;
+}
+
+/// Maps tool names to their handlers.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: HashMap<String, Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new(tools: Vec<Box<dyn Tool>>) -> Self {
+        ToolRegistry {
+            tools: tools
+                .into_iter()
+                .map(|tool| (tool.definition().name, tool))
+                .collect(),
+        }
+    }
+
+    /// All built-in tools, or none when `CHAT_TOOLS` is set to `0`/`false`
+    /// for providers that don't support tool calling.
+    pub fn from_env() -> Self {
+        if env::var("CHAT_TOOLS").is_ok_and(|value| value == "0" || value == "false") {
+            return ToolRegistry::default();
+        }
+
+        ToolRegistry::new(vec![
+            Box::new(epl_standing::GetStandings),
+            Box::new(imagine::GenerateImage),
+            Box::new(reminder::SetReminder),
+            Box::new(repost::LookupRepost),
+        ])
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        let mut definitions: Vec<_> = self
+            .tools
+            .values()
+            .map(|tool| ToolDefinition {
+                tool_type: "function".to_string(),
+                function: tool.definition(),
+            })
+            .collect();
+        definitions.sort_by(|a, b| a.function.name.cmp(&b.function.name));
+        definitions
+    }
+
+    /// Runs `tool_call` and returns its result. Failures are reported back to
+    /// the model as text so it can explain them instead of the bot erroring.
+    pub async fn call(&self, ctx: &Context, message: &Message, tool_call: &ToolCall) -> String {
+        let name = &tool_call.function.name;
+        let Some(tool) = self.tools.get(name) else {
+            return format!("Error: unknown tool {}", name);
+        };
+
+        let arguments = if tool_call.function.arguments.trim().is_empty() {
+            Value::Object(Default::default())
+        } else {
+            match serde_json::from_str(&tool_call.function.arguments) {
+                Ok(arguments) => arguments,
+                Err(e) => return format!("Error: arguments are not valid JSON: {}", e),
+            }
+        };
+
+        debug!(tool = name, ?arguments, "calling tool");
+        match tool.call(ctx, message, arguments).await {
+            Ok(result) => result,
+            Err(e) => {
+                warn!(tool = name, error = e, "tool failed");
+                format!("Error: {}", e)
+            }
+        }
+    }
+}
+
+/// Deserializes a tool's arguments into its typed parameters.
+fn parse_arguments<T: DeserializeOwned>(arguments: Value) -> Result<T, String> {
+    serde_json::from_value(arguments).map_err(|e| format!("invalid arguments: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definitions_are_sorted_functions() {
+        let definitions = ToolRegistry::new(vec![
+            Box::new(repost::LookupRepost),
+            Box::new(epl_standing::GetStandings),
+        ])
+        .definitions();
+
+        let names: Vec<_> = definitions
+            .iter()
+            .map(|definition| definition.function.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["get_epl_standings", "lookup_repost"]);
+        assert!(definitions
+            .iter()
+            .all(|definition| definition.tool_type == "function"));
+    }
+}
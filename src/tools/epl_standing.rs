@@ -0,0 +1,56 @@
+use chrono::{TimeZone, Utc};
+use serde_json::{json, Value};
+use serenity::{async_trait, model::prelude::Message, prelude::Context};
+
+use crate::commands::epl_standing::format_standings;
+use crate::tools::Tool;
+use crate::utils::{
+    openai::FunctionDefinition,
+    redis_client::RedisClient,
+    standings_cache::{get_cached_standings, Standings},
+};
+
+pub struct GetStandings;
+
+#[async_trait]
+impl Tool for GetStandings {
+    fn definition(&self) -> FunctionDefinition {
+        FunctionDefinition {
+            name: "get_epl_standings".to_string(),
+            description: "Get the current English Premier League table".to_string(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        }
+    }
+
+    async fn call(&self, ctx: &Context, _: &Message, _: Value) -> Result<String, String> {
+        let mut conn = {
+            let data = ctx.data.read().await;
+            data.get::<RedisClient>().unwrap().clone()
+        };
+        let (standings, stale_since) = match get_cached_standings(&mut conn).await {
+            Ok(Standings::Fresh(standings)) => (standings, None),
+            Ok(Standings::Stale {
+                standings,
+                fetched_at,
+            }) => (standings, Some(fetched_at)),
+            Err(e) => return Err(format!("failed to fetch standings: {}", e)),
+        };
+
+        let mut result = format!(
+            "{}\nLast updated: {}",
+            format_standings(&standings.standing),
+            standings.updated_at
+        );
+        if let Some(fetched_at) = stale_since {
+            let fetched_at = Utc
+                .timestamp_opt(fetched_at, 0)
+                .single()
+                .map_or_else(|| fetched_at.to_string(), |time| time.to_rfc3339());
+            result.push_str(&format!(
+                "\nThe standings couldn't be refreshed and may be out of date, they were fetched at {}",
+                fetched_at
+            ));
+        }
+        Ok(result)
+    }
+}
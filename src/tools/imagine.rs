@@ -0,0 +1,121 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serenity::{async_trait, model::prelude::Message, prelude::Context};
+
+use crate::commands::imagine::get_throttle;
+use crate::tools::{parse_arguments, Tool};
+use crate::utils::{
+    image_options::ImageOptions,
+    image_queue::ImageQueue,
+    image_upload::build_uploads,
+    moderation::{check_prompt, PromptSource},
+    openai::{generate_images, FunctionDefinition},
+    outbound::only_user,
+    rate_limit::{throttled_message, Feature},
+    redis_client::RedisClient,
+    usage::{check_quota, try_record_usage, UsageRecord},
+};
+
+#[derive(Debug, Deserialize)]
+struct Arguments {
+    prompt: String,
+}
+
+pub struct GenerateImage;
+
+#[async_trait]
+impl Tool for GenerateImage {
+    fn definition(&self) -> FunctionDefinition {
+        FunctionDefinition {
+            name: "generate_image".to_string(),
+            description: "Draw an image from a description. It is posted to the channel on its own, so don't link to it in your answer.".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "prompt": {
+                        "type": "string",
+                        "description": "Detailed description of the image",
+                    },
+                },
+                "required": ["prompt"],
+            }),
+        }
+    }
+
+    async fn call(
+        &self,
+        ctx: &Context,
+        message: &Message,
+        arguments: Value,
+    ) -> Result<String, String> {
+        let arguments: Arguments = parse_arguments(arguments)?;
+        let options = ImageOptions::default();
+        let mut conn = {
+            let data = ctx.data.read().await;
+            data.get::<RedisClient>().unwrap().clone()
+        };
+        let roles = message
+            .member
+            .as_ref()
+            .map(|member| member.roles.as_slice())
+            .unwrap_or_default();
+        let throttle = get_throttle(&mut conn, message.author.id, roles, message.channel_id).await;
+        if let Some(wait) = throttle {
+            return Err(throttled_message(wait));
+        }
+
+        let source = PromptSource {
+            user_id: message.author.id,
+            guild_id: message.guild_id,
+            channel_id: message.channel_id,
+            feature: Feature::Imagine,
+        };
+        check_prompt(&mut conn, &source, &arguments.prompt).await?;
+        check_quota(&mut conn, message.author.id, message.guild_id).await?;
+
+        let queue = {
+            let data = ctx.data.read().await;
+            data.get::<ImageQueue>().unwrap().clone()
+        };
+        let ticket = queue.enqueue().map_err(|e| e.friendly_message())?;
+        let permit = ticket
+            .wait_turn(|_| async {})
+            .await
+            .map_err(|e| e.friendly_message())?;
+        let images = generate_images(&arguments.prompt, &options)
+            .await
+            .map_err(|e| e.to_string())?;
+        drop(permit);
+
+        let usage = UsageRecord::images(&options.model, images.len());
+        try_record_usage(&mut conn, message.author.id, message.guild_id, &[usage]).await;
+
+        let (uploads, too_large) = build_uploads(&images, &arguments.prompt, &options.describe());
+        let mut posted = 0;
+        for (files, embeds) in uploads {
+            let count = files.len();
+            message
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    m.reference_message(message)
+                        .allowed_mentions(|am| only_user(am, message.author.id))
+                        .add_files(files)
+                        .add_embeds(embeds)
+                })
+                .await
+                .map_err(|e| e.to_string())?;
+            posted += count;
+        }
+
+        let revised_prompts: Vec<_> = images
+            .iter()
+            .filter_map(|image| image.revised_prompt.as_deref())
+            .collect();
+        Ok(json!({
+            "posted": posted,
+            "too_large": too_large,
+            "revised_prompts": revised_prompts,
+        })
+        .to_string())
+    }
+}
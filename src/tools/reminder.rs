@@ -0,0 +1,114 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serenity::{async_trait, model::prelude::Message, prelude::Context};
+use std::time::Duration;
+use tracing::error;
+
+use crate::tools::{parse_arguments, Tool};
+use crate::utils::openai::FunctionDefinition;
+use crate::utils::outbound::{escape_mass_mentions, only_user};
+
+const MAX_DELAY_MINUTES: u64 = 60 * 24;
+
+#[derive(Debug, Deserialize)]
+struct Arguments {
+    minutes: u64,
+    text: String,
+}
+
+fn validate(arguments: &Arguments) -> Result<Duration, String> {
+    if !(1..=MAX_DELAY_MINUTES).contains(&arguments.minutes) {
+        return Err(format!(
+            "minutes must be between 1 and {}",
+            MAX_DELAY_MINUTES
+        ));
+    }
+    if arguments.text.trim().is_empty() {
+        return Err("text must not be empty".to_string());
+    }
+    Ok(Duration::from_secs(arguments.minutes * 60))
+}
+
+/// Pings the asking user in the same channel later. Reminders only live in
+/// memory, a restart drops the pending ones.
+pub struct SetReminder;
+
+#[async_trait]
+impl Tool for SetReminder {
+    fn definition(&self) -> FunctionDefinition {
+        FunctionDefinition {
+            name: "set_reminder".to_string(),
+            description: "Remind the user about something in this channel after a delay"
+                .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "minutes": {
+                        "type": "integer",
+                        "minimum": 1,
+                        "maximum": MAX_DELAY_MINUTES,
+                        "description": "Minutes from now",
+                    },
+                    "text": { "type": "string", "description": "What to remind about" },
+                },
+                "required": ["minutes", "text"],
+            }),
+        }
+    }
+
+    async fn call(
+        &self,
+        ctx: &Context,
+        message: &Message,
+        arguments: Value,
+    ) -> Result<String, String> {
+        let arguments: Arguments = parse_arguments(arguments)?;
+        let delay = validate(&arguments)?;
+
+        let http = ctx.http.clone();
+        let channel_id = message.channel_id;
+        let user_id = message.author.id;
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let content = format!(
+                "<@{}> reminder: {}",
+                user_id,
+                escape_mass_mentions(&arguments.text)
+            );
+            let result = channel_id
+                .send_message(&http, |m| {
+                    m.content(content)
+                        .allowed_mentions(|am| only_user(am, user_id))
+                })
+                .await;
+            if let Err(e) = result {
+                error!("Failed to send reminder: {}", e);
+            }
+        });
+
+        Ok(format!(
+            "Reminder set for {} minutes from now",
+            arguments.minutes
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let arguments: Arguments =
+            parse_arguments(json!({ "minutes": 5, "text": "stretch" })).unwrap();
+        assert_eq!(validate(&arguments), Ok(Duration::from_secs(300)));
+
+        let arguments: Arguments = parse_arguments(json!({ "minutes": 0, "text": "x" })).unwrap();
+        assert!(validate(&arguments).is_err());
+
+        let arguments: Arguments = parse_arguments(json!({ "minutes": 5, "text": "  " })).unwrap();
+        assert!(validate(&arguments).is_err());
+
+        assert!(parse_arguments::<Arguments>(json!({ "text": "x" })).is_err());
+    }
+}
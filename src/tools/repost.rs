@@ -0,0 +1,51 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serenity::{async_trait, model::prelude::Message, prelude::Context};
+
+use crate::handlers::ming::{extract_urls, get_original_post};
+use crate::tools::{parse_arguments, Tool};
+use crate::utils::{openai::FunctionDefinition, redis_client::RedisClient};
+
+#[derive(Debug, Deserialize)]
+struct Arguments {
+    url: String,
+}
+
+/// Checks whether a link was already posted, using what `ming_handler` saw.
+pub struct LookupRepost;
+
+#[async_trait]
+impl Tool for LookupRepost {
+    fn definition(&self) -> FunctionDefinition {
+        FunctionDefinition {
+            name: "lookup_repost".to_string(),
+            description:
+                "Check whether a link has already been posted in the server during the last day"
+                    .to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "url": { "type": "string", "description": "The link to check" },
+                },
+                "required": ["url"],
+            }),
+        }
+    }
+
+    async fn call(&self, ctx: &Context, _: &Message, arguments: Value) -> Result<String, String> {
+        let arguments: Arguments = parse_arguments(arguments)?;
+        let urls = extract_urls(arguments.url).ok_or("no link found in url")?;
+
+        let mut conn = {
+            let data = ctx.data.read().await;
+            data.get::<RedisClient>().unwrap().clone()
+        };
+
+        let mut results = vec![];
+        for url in urls {
+            let original_post = get_original_post(&mut conn, &url).await;
+            results.push(json!({ "url": url, "original_post": original_post }));
+        }
+        Ok(Value::Array(results).to_string())
+    }
+}
@@ -1,2 +1,21 @@
+pub mod bot_user;
+pub mod chat_provider;
+pub mod chat_store;
+pub mod chat_turn;
+pub mod chunker;
+pub mod gallery;
+pub mod image_options;
+pub mod image_queue;
+pub mod image_upload;
+pub mod mentions;
+pub mod moderation;
+pub mod ollama;
 pub mod openai;
+pub mod openai_error;
+pub mod outbound;
+pub mod persona;
+pub mod rate_limit;
 pub mod redis_client;
+pub mod standings_cache;
+pub mod tokens;
+pub mod usage;
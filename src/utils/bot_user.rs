@@ -0,0 +1,8 @@
+use serenity::{model::id::UserId, prelude::TypeMapKey};
+
+/// The bot's own user ID, captured from `Ready` once the gateway connects.
+pub struct BotUser;
+
+impl TypeMapKey for BotUser {
+    type Value = UserId;
+}
@@ -0,0 +1,240 @@
+use serenity::async_trait;
+use std::env;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::utils::ollama::OllamaProvider;
+use crate::utils::openai::{ChatGPTRequest, ChatGPTResponse, OpenAiProvider};
+use crate::utils::openai_error::OpenAiError;
+
+/// A backend that can answer chat completion requests. Requests and
+/// responses use the OpenAI chat format, other backends translate from it.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Answers `request`. When it asks for `stream` and a `sender` is given,
+    /// every content delta is forwarded to it as it arrives.
+    async fn complete(
+        &self,
+        request: ChatGPTRequest,
+        sender: Option<&mpsc::UnboundedSender<String>>,
+    ) -> Result<ChatGPTResponse, OpenAiError>;
+}
+
+/// Tries each provider in order until one of them answers.
+pub struct Failover {
+    providers: Vec<Box<dyn ChatProvider>>,
+}
+
+#[async_trait]
+impl ChatProvider for Failover {
+    fn name(&self) -> &str {
+        "failover"
+    }
+
+    async fn complete(
+        &self,
+        request: ChatGPTRequest,
+        sender: Option<&mpsc::UnboundedSender<String>>,
+    ) -> Result<ChatGPTResponse, OpenAiError> {
+        let mut last_error = OpenAiError::InvalidRequest("No chat provider configured".to_string());
+        let request = &request;
+
+        for provider in &self.providers {
+            // Relay deltas through our own channel so we know whether the
+            // user has already seen part of this provider's answer.
+            let (attempt_sender, mut attempt_receiver) = mpsc::unbounded_channel();
+            let attempt = async move {
+                provider
+                    .complete(request.clone(), Some(&attempt_sender))
+                    .await
+            };
+            let relay = async {
+                let mut has_streamed = false;
+                while let Some(delta) = attempt_receiver.recv().await {
+                    has_streamed = true;
+                    if let Some(sender) = sender {
+                        let _ = sender.send(delta);
+                    }
+                }
+                has_streamed
+            };
+
+            let (result, has_streamed) = tokio::join!(attempt, relay);
+            match result {
+                Ok(response) => return Ok(response),
+                Err(e) if has_streamed => return Err(e),
+                Err(e) => {
+                    warn!(
+                        provider = provider.name(),
+                        error = %e,
+                        "chat provider failed"
+                    );
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Builds the providers listed in `CHAT_PROVIDERS` (comma separated, tried in
+/// order), defaulting to OpenAI. Each one is configured from its own
+/// variables:
+///
+/// - `openai`: `OPENAI_KEY`
+/// - `openai_compatible`: `OPENAI_COMPATIBLE_BASE_URL`, optional
+///   `OPENAI_COMPATIBLE_KEY`, `OPENAI_COMPATIBLE_MODEL_ID` and
+///   `OPENAI_COMPATIBLE_INCLUDE_USAGE` for servers that report usage in
+///   streams
+/// - `ollama`: optional `OLLAMA_BASE_URL` and `OLLAMA_MODEL_ID`
+pub fn from_env() -> Failover {
+    let names = env::var("CHAT_PROVIDERS").unwrap_or_else(|_| "openai".to_string());
+    let providers = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| -> Option<Box<dyn ChatProvider>> {
+            match name {
+                "openai" => Some(Box::new(OpenAiProvider::openai())),
+                "openai_compatible" => match OpenAiProvider::compatible_from_env() {
+                    Some(provider) => Some(Box::new(provider)),
+                    None => {
+                        warn!("OPENAI_COMPATIBLE_BASE_URL must be set to use openai_compatible");
+                        None
+                    }
+                },
+                "ollama" => Some(Box::new(OllamaProvider::from_env())),
+                unknown => {
+                    warn!("Unknown chat provider: {}", unknown);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    Failover { providers }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::openai::{Choice, Message};
+
+    struct FakeProvider {
+        deltas: Vec<&'static str>,
+        result: Result<&'static str, &'static str>,
+    }
+
+    #[async_trait]
+    impl ChatProvider for FakeProvider {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        async fn complete(
+            &self,
+            _request: ChatGPTRequest,
+            sender: Option<&mpsc::UnboundedSender<String>>,
+        ) -> Result<ChatGPTResponse, OpenAiError> {
+            for delta in &self.deltas {
+                sender.unwrap().send(delta.to_string()).unwrap();
+            }
+            self.result
+                .map(|content| ChatGPTResponse {
+                    choices: vec![Choice {
+                        message: Message {
+                            role: "assistant".to_string(),
+                            content: content.into(),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                })
+                .map_err(|e| OpenAiError::Transport(e.to_string()))
+        }
+    }
+
+    fn request() -> ChatGPTRequest {
+        ChatGPTRequest {
+            model: "test".to_string(),
+            messages: vec![],
+            temperature: 1.0,
+            stream: true,
+            stream_options: None,
+            tools: vec![],
+        }
+    }
+
+    async fn complete(providers: Vec<FakeProvider>) -> (Result<String, OpenAiError>, Vec<String>) {
+        let failover = Failover {
+            providers: providers
+                .into_iter()
+                .map(|provider| Box::new(provider) as Box<dyn ChatProvider>)
+                .collect(),
+        };
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let result = failover
+            .complete(request(), Some(&sender))
+            .await
+            .map(|response| response.choices[0].message.content.text());
+        drop(sender);
+
+        let mut deltas = vec![];
+        while let Some(delta) = receiver.recv().await {
+            deltas.push(delta);
+        }
+        (result, deltas)
+    }
+
+    #[tokio::test]
+    async fn test_failover_uses_next_provider() {
+        let (result, deltas) = complete(vec![
+            FakeProvider {
+                deltas: vec![],
+                result: Err("down"),
+            },
+            FakeProvider {
+                deltas: vec!["he", "llo"],
+                result: Ok("hello"),
+            },
+        ])
+        .await;
+
+        assert_eq!(result.unwrap(), "hello");
+        assert_eq!(deltas, vec!["he", "llo"]);
+    }
+
+    #[tokio::test]
+    async fn test_failover_stops_after_partial_stream() {
+        let (result, deltas) = complete(vec![
+            FakeProvider {
+                deltas: vec!["he"],
+                result: Err("connection reset"),
+            },
+            FakeProvider {
+                deltas: vec!["hello"],
+                result: Ok("hello"),
+            },
+        ])
+        .await;
+
+        assert_eq!(
+            result.unwrap_err(),
+            OpenAiError::Transport("connection reset".to_string())
+        );
+        assert_eq!(deltas, vec!["he"]);
+    }
+
+    #[tokio::test]
+    async fn test_failover_without_providers() {
+        let (result, _) = complete(vec![]).await;
+        assert_eq!(
+            result.unwrap_err(),
+            OpenAiError::InvalidRequest("No chat provider configured".to_string())
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use redis::{AsyncCommands, RedisResult};
+use serenity::model::id::{ChannelId, UserId};
+use std::env;
+
+use crate::utils::redis_client::RedisManager;
+
+/// How long a thread is kept after its last turn when `CHAT_HISTORY_TTL`
+/// isn't set: 30 days.
+const DEFAULT_TTL_SECONDS: usize = 60 * 60 * 24 * 30;
+
+/// Seconds a thread lives after its last turn, `None` when `CHAT_HISTORY_TTL`
+/// is `0` and threads are kept forever.
+pub fn get_ttl() -> Option<usize> {
+    let ttl = env::var("CHAT_HISTORY_TTL")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECONDS);
+    (ttl > 0).then_some(ttl)
+}
+
+/// Set of the `msg_*` and `tools_*` keys that belong to a thread, so they can
+/// be refreshed and deleted along with it.
+fn members_key(chat_key: &str) -> String {
+    format!("keys_{}", chat_key)
+}
+
+/// Set of the index sets a thread is listed in, so it can be taken out of
+/// them when it's deleted and keep them alive while it's in use.
+fn indexes_key(chat_key: &str) -> String {
+    format!("indexes_{}", chat_key)
+}
+
+pub fn summary_key(chat_key: &str) -> String {
+    format!("summary_{}", chat_key)
+}
+
+fn user_threads_key(user_id: UserId) -> String {
+    format!("threads_user_{}", user_id)
+}
+
+fn channel_threads_key(channel_id: ChannelId) -> String {
+    format!("threads_channel_{}", channel_id)
+}
+
+fn last_thread_key(channel_id: ChannelId, user_id: UserId) -> String {
+    format!("last_thread_{}_{}", channel_id, user_id)
+}
+
+/// Keys a thread owns besides its members.
+fn thread_keys(chat_key: &str) -> [String; 4] {
+    [
+        chat_key.to_string(),
+        summary_key(chat_key),
+        members_key(chat_key),
+        indexes_key(chat_key),
+    ]
+}
+
+/// Records `keys` as part of the thread stored at `chat_key`.
+pub async fn track_keys(
+    conn: &mut RedisManager,
+    chat_key: &str,
+    keys: &[String],
+) -> RedisResult<()> {
+    let mut pipe = redis::pipe();
+    pipe.sadd(members_key(chat_key), keys).ignore();
+    if let Some(ttl) = get_ttl() {
+        for key in keys {
+            pipe.expire(key, ttl).ignore();
+        }
+    }
+    pipe.query_async(conn).await
+}
+
+/// Indexes a new thread under the user who started it and its channel.
+pub async fn index_thread(
+    conn: &mut RedisManager,
+    chat_key: &str,
+    user_id: UserId,
+    channel_id: ChannelId,
+) -> RedisResult<()> {
+    let index_keys = [user_threads_key(user_id), channel_threads_key(channel_id)];
+    let mut pipe = redis::pipe();
+    for index_key in &index_keys {
+        pipe.sadd(index_key, chat_key).ignore();
+    }
+    pipe.sadd(indexes_key(chat_key), &index_keys).ignore();
+    pipe.query_async(conn).await
+}
+
+/// Remembers `chat_key` as the thread `user_id` last talked in, for
+/// `/forget thread`.
+pub async fn set_last_thread(
+    conn: &mut RedisManager,
+    chat_key: &str,
+    user_id: UserId,
+    channel_id: ChannelId,
+) -> RedisResult<()> {
+    let key = last_thread_key(channel_id, user_id);
+    match get_ttl() {
+        Some(ttl) => conn.set_ex(key, chat_key, ttl).await,
+        None => conn.set(key, chat_key).await,
+    }
+}
+
+pub async fn get_last_thread(
+    conn: &mut RedisManager,
+    user_id: UserId,
+    channel_id: ChannelId,
+) -> RedisResult<Option<String>> {
+    conn.get(last_thread_key(channel_id, user_id)).await
+}
+
+/// The thread a message belongs to, if it is part of one.
+pub async fn get_thread_of(
+    conn: &mut RedisManager,
+    message_id: u64,
+) -> RedisResult<Option<String>> {
+    conn.get(format!("msg_{}", message_id)).await
+}
+
+pub async fn is_thread_of_user(
+    conn: &mut RedisManager,
+    chat_key: &str,
+    user_id: UserId,
+) -> RedisResult<bool> {
+    conn.sismember(user_threads_key(user_id), chat_key).await
+}
+
+/// Restarts the clock on every key of the thread and the index sets it is
+/// listed in. Called when the thread is created and after each turn.
+pub async fn refresh_thread(conn: &mut RedisManager, chat_key: &str) -> RedisResult<()> {
+    let Some(ttl) = get_ttl() else {
+        return Ok(());
+    };
+
+    let members: Vec<String> = conn.smembers(members_key(chat_key)).await?;
+    let index_keys: Vec<String> = conn.smembers(indexes_key(chat_key)).await?;
+    let mut pipe = redis::pipe();
+    for key in thread_keys(chat_key)
+        .iter()
+        .chain(&members)
+        .chain(&index_keys)
+    {
+        pipe.expire(key, ttl).ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+/// Deletes a thread with all of its pointers, summary and tool messages, and
+/// takes it out of the index sets.
+pub async fn delete_thread(conn: &mut RedisManager, chat_key: &str) -> RedisResult<()> {
+    let members: Vec<String> = conn.smembers(members_key(chat_key)).await?;
+    let index_keys: Vec<String> = conn.smembers(indexes_key(chat_key)).await?;
+    let keys: Vec<String> = thread_keys(chat_key).into_iter().chain(members).collect();
+    let mut pipe = redis::pipe();
+    for index_key in &index_keys {
+        pipe.srem(index_key, chat_key).ignore();
+    }
+    pipe.del(keys).ignore();
+    pipe.query_async(conn).await
+}
+
+/// Deletes every thread in an index set, then the set itself. Returns how
+/// many threads were still around.
+async fn delete_indexed_threads(conn: &mut RedisManager, index_key: &str) -> RedisResult<usize> {
+    let chat_keys: Vec<String> = conn.smembers(index_key).await?;
+    let mut deleted = 0;
+    for chat_key in &chat_keys {
+        if conn.exists(chat_key).await? {
+            deleted += 1;
+        }
+        delete_thread(conn, chat_key).await?;
+    }
+    conn.del::<_, ()>(index_key).await?;
+    Ok(deleted)
+}
+
+/// Deletes every thread `user_id` started.
+pub async fn delete_user_threads(conn: &mut RedisManager, user_id: UserId) -> RedisResult<usize> {
+    delete_indexed_threads(conn, &user_threads_key(user_id)).await
+}
+
+/// Deletes every thread started in `channel_id`.
+pub async fn delete_channel_threads(
+    conn: &mut RedisManager,
+    channel_id: ChannelId,
+) -> RedisResult<usize> {
+    delete_indexed_threads(conn, &channel_threads_key(channel_id)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_keys() {
+        assert_eq!(
+            thread_keys("chat_1"),
+            ["chat_1", "summary_chat_1", "keys_chat_1", "indexes_chat_1"]
+        );
+        assert_eq!(user_threads_key(UserId(2)), "threads_user_2");
+        assert_eq!(channel_threads_key(ChannelId(3)), "threads_channel_3");
+        assert_eq!(last_thread_key(ChannelId(3), UserId(2)), "last_thread_3_2");
+    }
+}
@@ -0,0 +1,191 @@
+use serde::{Deserialize, Serialize};
+use serenity::model::{
+    channel::Attachment,
+    id::{MessageId, UserId},
+    prelude::Message,
+};
+
+/// Written into every stored turn, bump it when the format changes in a way
+/// older readers can't handle.
+pub const CHAT_TURN_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TurnRole {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TurnAttachment {
+    pub url: String,
+    pub filename: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// In bytes.
+    pub size: u64,
+}
+
+impl From<&Attachment> for TurnAttachment {
+    fn from(attachment: &Attachment) -> Self {
+        TurnAttachment {
+            url: attachment.url.clone(),
+            filename: attachment.filename.clone(),
+            content_type: attachment.content_type.clone(),
+            size: attachment.size,
+        }
+    }
+}
+
+/// One message of a chat thread, as stored in its `chat_` list. Only keeps
+/// what the model needs, unlike the full serenity [`Message`] stored before.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatTurn {
+    pub version: u32,
+    pub message_id: MessageId,
+    pub author_id: UserId,
+    pub display_name: String,
+    pub role: TurnRole,
+    pub content: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<TurnAttachment>,
+    /// Messages the rest of a long answer was sent in, after `message_id`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub follow_up_ids: Vec<MessageId>,
+    /// Unix timestamp in seconds.
+    pub timestamp: i64,
+}
+
+impl ChatTurn {
+    /// Turns written by `bot_id` are the assistant's, all others the users'.
+    pub fn from_message(message: &Message, bot_id: UserId) -> Self {
+        ChatTurn {
+            version: CHAT_TURN_VERSION,
+            message_id: message.id,
+            author_id: message.author.id,
+            display_name: message
+                .member
+                .as_ref()
+                .and_then(|member| member.nick.clone())
+                .unwrap_or_else(|| message.author.name.clone()),
+            role: if message.author.id == bot_id {
+                TurnRole::Assistant
+            } else {
+                TurnRole::User
+            },
+            content: message.content.clone(),
+            attachments: message
+                .attachments
+                .iter()
+                .map(TurnAttachment::from)
+                .collect(),
+            follow_up_ids: vec![],
+            timestamp: message.timestamp.unix_timestamp(),
+        }
+    }
+}
+
+/// Reads a stored turn, converting the serialized serenity [`Message`] older
+/// versions stored. The flag tells whether it was in the old format and
+/// should be written back.
+pub fn parse_turn(value: &str, bot_id: UserId) -> Result<(ChatTurn, bool), serde_json::Error> {
+    match serde_json::from_str::<ChatTurn>(value) {
+        Ok(turn) => Ok((turn, false)),
+        Err(e) => match serde_json::from_str::<Message>(value) {
+            Ok(message) => Ok((ChatTurn::from_message(&message, bot_id), true)),
+            Err(_) => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const BOT_ID: u64 = 1042057406525485096;
+
+    fn message_json(author_id: u64) -> serde_json::Value {
+        json!({
+            "id": "5",
+            "channel_id": "1",
+            "author": {
+                "id": author_id.to_string(),
+                "username": "alice",
+                "discriminator": "0",
+                "avatar": null,
+            },
+            "member": {
+                "deaf": false,
+                "mute": false,
+                "nick": "Ally",
+                "roles": [],
+            },
+            "content": "look at this",
+            "timestamp": "2023-10-18T00:00:00Z",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [{
+                "id": "6",
+                "filename": "cat.png",
+                "size": 1024,
+                "url": "https://cdn.discordapp.com/6",
+                "proxy_url": "https://media.discordapp.net/6",
+                "content_type": "image/png",
+            }],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        })
+    }
+
+    #[test]
+    fn test_from_message() {
+        let message: Message = serde_json::from_value(message_json(10)).unwrap();
+
+        let turn = ChatTurn::from_message(&message, UserId(BOT_ID));
+        assert_eq!(turn.message_id, MessageId(5));
+        assert_eq!(turn.author_id, UserId(10));
+        assert_eq!(turn.display_name, "Ally");
+        assert_eq!(turn.role, TurnRole::User);
+        assert_eq!(turn.content, "look at this");
+        assert_eq!(turn.attachments[0].filename, "cat.png");
+        assert_eq!(
+            turn.attachments[0].content_type.as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(turn.timestamp, 1697587200);
+
+        let message: Message = serde_json::from_value(message_json(BOT_ID)).unwrap();
+        let turn = ChatTurn::from_message(&message, UserId(BOT_ID));
+        assert_eq!(turn.role, TurnRole::Assistant);
+    }
+
+    #[test]
+    fn test_parse_turn_round_trip() {
+        let message: Message = serde_json::from_value(message_json(10)).unwrap();
+        let turn = ChatTurn::from_message(&message, UserId(BOT_ID));
+
+        let value = serde_json::to_string(&turn).unwrap();
+        assert_eq!(parse_turn(&value, UserId(BOT_ID)).unwrap(), (turn, false));
+    }
+
+    #[test]
+    fn test_parse_turn_migrates_messages() {
+        let value = message_json(BOT_ID).to_string();
+
+        let (turn, is_legacy) = parse_turn(&value, UserId(BOT_ID)).unwrap();
+        assert!(is_legacy);
+        assert_eq!(turn.role, TurnRole::Assistant);
+        assert_eq!(turn.content, "look at this");
+        assert!(serde_json::to_string(&turn).unwrap().len() < value.len());
+
+        assert!(parse_turn("{}", UserId(BOT_ID)).is_err());
+    }
+}
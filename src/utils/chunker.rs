@@ -0,0 +1,159 @@
+/// Longest message Discord accepts, in characters.
+pub const DISCORD_MESSAGE_LIMIT: usize = 2000;
+
+const FENCE: &str = "```";
+
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with(FENCE)
+}
+
+/// Fence left open after `lines`, given the one open before them.
+fn fence_after(opening: Option<&str>, lines: &[&str]) -> Option<String> {
+    lines
+        .iter()
+        .filter(|line| is_fence(line))
+        .fold(opening.map(str::to_string), |fence, line| match fence {
+            Some(_) => None,
+            None => Some(line.trim().to_string()),
+        })
+}
+
+/// Renders `lines` as one message, reopening the `opening` fence at the top
+/// and closing whichever fence is still open at the bottom.
+fn render(opening: Option<&str>, lines: &[&str]) -> (String, Option<String>) {
+    let mut chunk = String::new();
+    if let Some(opening) = opening {
+        chunk.push_str(opening);
+        chunk.push('\n');
+    }
+    chunk.push_str(&lines.join("\n"));
+
+    let fence = fence_after(opening, lines);
+    if fence.is_some() {
+        chunk.push('\n');
+        chunk.push_str(FENCE);
+    }
+    (chunk, fence)
+}
+
+fn rendered_len(opening: Option<&str>, lines: &[&str]) -> usize {
+    render(opening, lines).0.chars().count()
+}
+
+/// Index to split `lines` at so the first part ends with a blank line outside
+/// of a code block, if there is one in the second half.
+fn find_paragraph_break(opening: Option<&str>, lines: &[&str]) -> Option<usize> {
+    (lines.len() / 2..lines.len())
+        .rev()
+        .filter(|&index| index > 0 && lines[index - 1].trim().is_empty())
+        .find(|&index| fence_after(opening, &lines[..index]).is_none())
+}
+
+/// Breaks a line longer than `max` characters, preferably after whitespace.
+fn split_long_line(line: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = line;
+    while rest.chars().count() > max {
+        let hard_end = rest
+            .char_indices()
+            .nth(max)
+            .map_or(rest.len(), |(index, _)| index);
+        let end = match rest[..hard_end].rfind(char::is_whitespace) {
+            Some(index) if index > 0 => index + 1,
+            _ => hard_end,
+        };
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    pieces.push(rest);
+    pieces
+}
+
+/// Splits `text` into messages of at most `limit` characters. Splits happen
+/// between paragraphs where possible, otherwise between lines, and code
+/// blocks cut in two are closed and reopened with the same language.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut opening: Option<String> = None;
+    let mut current: Vec<&str> = Vec::new();
+
+    let lines = text
+        .lines()
+        .flat_map(|line| split_long_line(line, limit / 2));
+    for line in lines {
+        current.push(line);
+        while current.len() > 1 && rendered_len(opening.as_deref(), &current) > limit {
+            let line = current.pop().unwrap();
+            let split = find_paragraph_break(opening.as_deref(), &current).unwrap_or(current.len());
+            let rest = current.split_off(split);
+
+            let (chunk, fence) = render(opening.as_deref(), &current);
+            if !chunk.trim().is_empty() {
+                chunks.push(chunk.trim_end().to_string());
+            }
+            opening = fence;
+            current = rest;
+            current.push(line);
+        }
+    }
+
+    let (chunk, _) = render(opening.as_deref(), &current);
+    if !chunk.trim().is_empty() {
+        chunks.push(chunk.trim_end().to_string());
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message_short() {
+        assert_eq!(split_message("hello\nworld", 20), vec!["hello\nworld"]);
+        assert!(split_message("", 20).is_empty());
+    }
+
+    #[test]
+    fn test_split_message_prefers_paragraphs() {
+        let text = "aaaa aaaa\n\nbbbb\ncccc\ndddd";
+
+        let chunks = split_message(text, 20);
+        assert_eq!(chunks, vec!["aaaa aaaa", "bbbb\ncccc\ndddd"]);
+    }
+
+    #[test]
+    fn test_split_message_reopens_code_blocks() {
+        let text = "Here:\n```rust\nlet a = 1;\nlet b = 2;\nlet c = 3;\n```\nDone";
+
+        let chunks = split_message(text, 30);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 30));
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.matches(FENCE).count() % 2 == 0));
+        assert!(chunks[1].starts_with("```rust\n"));
+        assert_eq!(
+            chunks.join("\n").replace("\n```\n```rust", ""),
+            text.to_string()
+        );
+    }
+
+    #[test]
+    fn test_split_message_long_line() {
+        let text = "word ".repeat(30);
+
+        let chunks = split_message(&text, 40);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 40));
+        assert_eq!(chunks.join(" ").split_whitespace().count(), 30);
+    }
+
+    #[test]
+    fn test_split_message_counts_characters() {
+        let text = "你好".repeat(15);
+
+        let chunks = split_message(&text, 20);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 20));
+        assert_eq!(chunks.concat(), text);
+    }
+}
@@ -0,0 +1,274 @@
+use chrono::Utc;
+use redis::{AsyncCommands, RedisResult};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    http::Http,
+    model::{
+        channel::Message,
+        id::{ChannelId, GuildId, MessageId, UserId},
+    },
+};
+use tracing::warn;
+
+use crate::utils::{
+    image_options::ImageOptions, image_upload::image_index, openai::GeneratedImage,
+    redis_client::RedisManager,
+};
+
+/// Newest images kept per gallery.
+const MAX_GALLERY_ENTRIES: isize = 500;
+
+const NEXT_ID_KEY: &str = "gallery_next_id";
+
+/// One image drawn with `/imagine`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GalleryEntry {
+    pub id: u64,
+    pub prompt: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revised_prompt: Option<String>,
+    pub author_id: UserId,
+    /// Unix timestamp in seconds.
+    pub timestamp: i64,
+    /// The uploaded attachment, as of when it was uploaded. Discord's links
+    /// expire, see [`GalleryEntry::upload`].
+    pub url: String,
+    /// Where it was uploaded, to get a fresh link from. Unset for entries
+    /// recorded before it was kept.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload: Option<UploadLocation>,
+    /// Settings it was drawn with. Only set for images drawn from the prompt
+    /// alone, which are the ones that can be re-rolled.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<ImageOptions>,
+}
+
+/// The attachment an image was uploaded as.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadLocation {
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub filename: String,
+}
+
+impl GalleryEntry {
+    /// A link to the image that hasn't expired, falling back to the stored
+    /// one when the message can't be fetched anymore.
+    pub async fn fresh_url(&self, http: &Http) -> String {
+        let Some(upload) = &self.upload else {
+            return self.url.clone();
+        };
+        let message = match upload.channel_id.message(http, upload.message_id).await {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to fetch gallery image {}: {}", self.id, e);
+                return self.url.clone();
+            }
+        };
+        message
+            .attachments
+            .into_iter()
+            .find(|attachment| attachment.filename == upload.filename)
+            .map_or_else(|| self.url.clone(), |attachment| attachment.url)
+    }
+}
+
+/// Images are kept per server, or per channel outside of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GalleryScope {
+    Guild(GuildId),
+    Channel(ChannelId),
+}
+
+impl GalleryScope {
+    pub fn new(guild_id: Option<GuildId>, channel_id: ChannelId) -> Self {
+        match guild_id {
+            Some(guild_id) => GalleryScope::Guild(guild_id),
+            None => GalleryScope::Channel(channel_id),
+        }
+    }
+
+    fn key(&self) -> String {
+        match self {
+            GalleryScope::Guild(guild_id) => format!("gallery_guild_{}", guild_id),
+            GalleryScope::Channel(channel_id) => format!("gallery_channel_{}", channel_id),
+        }
+    }
+}
+
+/// What was drawn, for recording the message the images were uploaded in.
+pub struct UploadedImages<'a> {
+    pub prompt: &'a str,
+    pub images: &'a [GeneratedImage],
+    pub author_id: UserId,
+    /// See [`GalleryEntry::options`].
+    pub options: Option<&'a ImageOptions>,
+}
+
+/// Entries for the images attached to `message`, without ids yet.
+fn entries_from_message(message: &Message, upload: &UploadedImages) -> Vec<GalleryEntry> {
+    message
+        .attachments
+        .iter()
+        .filter_map(|attachment| {
+            let image = upload.images.get(image_index(&attachment.filename)?)?;
+            Some(GalleryEntry {
+                id: 0,
+                prompt: upload.prompt.to_string(),
+                revised_prompt: image.revised_prompt.clone(),
+                author_id: upload.author_id,
+                timestamp: Utc::now().timestamp(),
+                url: attachment.url.clone(),
+                upload: Some(UploadLocation {
+                    channel_id: message.channel_id,
+                    message_id: message.id,
+                    filename: attachment.filename.clone(),
+                }),
+                options: upload.options.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// Adds the images uploaded in `message` to the gallery, newest first,
+/// dropping the oldest beyond [`MAX_GALLERY_ENTRIES`].
+pub async fn record_upload(
+    conn: &mut RedisManager,
+    scope: GalleryScope,
+    message: &Message,
+    upload: &UploadedImages<'_>,
+) -> RedisResult<()> {
+    let mut entries = entries_from_message(message, upload);
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let last_id: u64 = conn.incr(NEXT_ID_KEY, entries.len()).await?;
+    let first_id = last_id + 1 - entries.len() as u64;
+    for (offset, entry) in entries.iter_mut().enumerate() {
+        entry.id = first_id + offset as u64;
+    }
+
+    let key = scope.key();
+    let values: Vec<String> = entries
+        .iter()
+        .map(|entry| serde_json::to_string(entry).unwrap())
+        .collect();
+    redis::pipe()
+        .lpush(&key, values)
+        .ignore()
+        .ltrim(&key, 0, MAX_GALLERY_ENTRIES - 1)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+/// Every image in the gallery, newest first.
+pub async fn get_entries(
+    conn: &mut RedisManager,
+    scope: GalleryScope,
+) -> RedisResult<Vec<GalleryEntry>> {
+    let values: Vec<String> = conn.lrange(scope.key(), 0, -1).await?;
+    Ok(values
+        .iter()
+        .filter_map(|value| match serde_json::from_str(value) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                warn!("Skipping unreadable gallery entry: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Entries drawn by `author_id` whose prompts contain `keyword`, ignoring
+/// case, when given.
+pub fn filter_entries(
+    entries: Vec<GalleryEntry>,
+    author_id: Option<UserId>,
+    keyword: Option<&str>,
+) -> Vec<GalleryEntry> {
+    let keyword = keyword.map(str::to_lowercase);
+    entries
+        .into_iter()
+        .filter(|entry| author_id.is_none_or(|author_id| entry.author_id == author_id))
+        .filter(|entry| {
+            keyword.as_ref().is_none_or(|keyword| {
+                entry.prompt.to_lowercase().contains(keyword)
+                    || entry
+                        .revised_prompt
+                        .as_ref()
+                        .is_some_and(|revised| revised.to_lowercase().contains(keyword))
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u64, author_id: u64, prompt: &str, revised_prompt: Option<&str>) -> GalleryEntry {
+        GalleryEntry {
+            id,
+            prompt: prompt.to_string(),
+            revised_prompt: revised_prompt.map(str::to_string),
+            author_id: UserId(author_id),
+            timestamp: 1697587200,
+            url: format!("https://cdn.discordapp.com/{}", id),
+            upload: None,
+            options: None,
+        }
+    }
+
+    #[test]
+    fn test_entry_without_upload() {
+        let json = r#"{"id":1,"prompt":"cat","author_id":"10","timestamp":1697587200,"url":"https://cdn.discordapp.com/1"}"#;
+        let entry: GalleryEntry = serde_json::from_str(json).unwrap();
+        assert_eq!(entry, self::entry(1, 10, "cat", None));
+        assert_eq!(serde_json::to_string(&entry).unwrap(), json);
+    }
+
+    #[test]
+    fn test_scope_key() {
+        assert_eq!(
+            GalleryScope::new(Some(GuildId(1)), ChannelId(2)).key(),
+            "gallery_guild_1"
+        );
+        assert_eq!(
+            GalleryScope::new(None, ChannelId(2)).key(),
+            "gallery_channel_2"
+        );
+    }
+
+    #[test]
+    fn test_filter_entries() {
+        let entries = vec![
+            entry(1, 10, "A cat in space", None),
+            entry(2, 20, "dog", Some("A dog wearing a space helmet")),
+            entry(3, 10, "sunset", None),
+        ];
+        let ids = |entries: Vec<GalleryEntry>| -> Vec<u64> {
+            entries.into_iter().map(|entry| entry.id).collect()
+        };
+
+        assert_eq!(
+            ids(filter_entries(entries.clone(), None, None)),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            ids(filter_entries(entries.clone(), Some(UserId(10)), None)),
+            vec![1, 3]
+        );
+        assert_eq!(
+            ids(filter_entries(entries.clone(), None, Some("SPACE"))),
+            vec![1, 2]
+        );
+        assert_eq!(
+            ids(filter_entries(entries, Some(UserId(20)), Some("space"))),
+            vec![2]
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+
+/// What an image model accepts, per the OpenAI docs.
+#[derive(Debug, PartialEq)]
+pub struct ImageModel {
+    pub name: &'static str,
+    /// The first one is the model's default.
+    pub sizes: &'static [&'static str],
+    pub qualities: &'static [&'static str],
+    pub styles: &'static [&'static str],
+    pub max_images: u32,
+    /// Whether it can edit an uploaded image or draw variations of it.
+    pub edits: bool,
+}
+
+pub const IMAGE_MODELS: &[ImageModel] = &[
+    ImageModel {
+        name: "dall-e-3",
+        sizes: &["1024x1024", "1792x1024", "1024x1792"],
+        qualities: &["standard", "hd"],
+        styles: &["vivid", "natural"],
+        max_images: 1,
+        edits: false,
+    },
+    ImageModel {
+        name: "dall-e-2",
+        sizes: &["1024x1024", "512x512", "256x256"],
+        qualities: &[],
+        styles: &[],
+        max_images: 4,
+        edits: true,
+    },
+];
+
+pub fn get_image_model(name: &str) -> Option<&'static ImageModel> {
+    IMAGE_MODELS.iter().find(|model| model.name == name)
+}
+
+/// Models `/imagine` offers, from the comma separated `IMAGE_MODELS`. The
+/// first one is the default, `dall-e-3` when none are configured.
+pub fn get_configured_models() -> Vec<&'static ImageModel> {
+    let models: Vec<_> = env::var("IMAGE_MODELS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|name| get_image_model(name.trim()))
+        .collect();
+    if models.is_empty() {
+        vec![&IMAGE_MODELS[0]]
+    } else {
+        models
+    }
+}
+
+/// Model edits and variations default to: the first configured one that
+/// can do them, or else the first known one.
+pub fn get_edit_model() -> &'static ImageModel {
+    edit_model_from(&get_configured_models())
+}
+
+fn edit_model_from(configured: &[&'static ImageModel]) -> &'static ImageModel {
+    configured
+        .iter()
+        .copied()
+        .find(|model| model.edits)
+        .or_else(|| IMAGE_MODELS.iter().find(|model| model.edits))
+        .expect("an image model supports edits")
+}
+
+/// Settings for one image generation. Unset ones are left to the model.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageOptions {
+    pub model: String,
+    pub size: Option<String>,
+    pub quality: Option<String>,
+    pub style: Option<String>,
+    pub n: u32,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            model: get_configured_models()[0].name.to_string(),
+            size: None,
+            quality: None,
+            style: None,
+            n: 1,
+        }
+    }
+}
+
+fn check_choice(
+    kind: &str,
+    value: &Option<String>,
+    allowed: &[&str],
+    model: &str,
+) -> Result<(), String> {
+    match value {
+        Some(value) if !allowed.contains(&value.as_str()) => Err(if allowed.is_empty() {
+            format!("{} doesn't support choosing the {}.", model, kind)
+        } else {
+            format!(
+                "{} only supports these {}s: {}.",
+                model,
+                kind,
+                allowed.join(", ")
+            )
+        }),
+        _ => Ok(()),
+    }
+}
+
+impl ImageOptions {
+    /// Checks the settings against what the model accepts, returning the
+    /// message to show when they don't fit.
+    pub fn validate(&self) -> Result<(), String> {
+        let model = get_image_model(&self.model)
+            .ok_or_else(|| format!("Unknown image model {}.", self.model))?;
+        check_choice("size", &self.size, model.sizes, model.name)?;
+        check_choice("quality", &self.quality, model.qualities, model.name)?;
+        check_choice("style", &self.style, model.styles, model.name)?;
+        if !(1..=model.max_images).contains(&self.n) {
+            return Err(match model.max_images {
+                1 => format!("{} draws one image at a time.", model.name),
+                max => format!(
+                    "{} can draw between 1 and {} images at a time.",
+                    model.name, max
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Like [`ImageOptions::validate`], for editing an image or drawing
+    /// variations of it.
+    pub fn validate_edit(&self) -> Result<(), String> {
+        self.validate()?;
+        match get_image_model(&self.model) {
+            Some(model) if !model.edits => Err(format!(
+                "{} can't work from an image, try {}.",
+                model.name,
+                get_edit_model().name
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The settings used, with the model's defaults filled in, for showing
+    /// along with the images.
+    pub fn describe(&self) -> String {
+        let model = get_image_model(&self.model);
+        let mut parts = vec![self.model.clone()];
+        parts.extend(
+            self.size
+                .clone()
+                .or_else(|| Some(model?.sizes.first()?.to_string())),
+        );
+        parts.extend(
+            self.quality
+                .clone()
+                .or_else(|| Some(model?.qualities.first()?.to_string())),
+        );
+        parts.extend(
+            self.style
+                .clone()
+                .or_else(|| Some(model?.styles.first()?.to_string())),
+        );
+        if self.n > 1 {
+            parts.push(format!("{} images", self.n));
+        }
+        parts.join(" · ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(model: &str) -> ImageOptions {
+        ImageOptions {
+            model: model.to_string(),
+            size: None,
+            quality: None,
+            style: None,
+            n: 1,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(options("dall-e-3").validate().is_ok());
+        assert!(options("midjourney").validate().is_err());
+
+        let hd = ImageOptions {
+            size: Some("1792x1024".to_string()),
+            quality: Some("hd".to_string()),
+            style: Some("natural".to_string()),
+            ..options("dall-e-3")
+        };
+        assert!(hd.validate().is_ok());
+
+        let too_many = ImageOptions {
+            n: 2,
+            ..options("dall-e-3")
+        };
+        assert_eq!(
+            too_many.validate().unwrap_err(),
+            "dall-e-3 draws one image at a time."
+        );
+
+        let small = ImageOptions {
+            size: Some("256x256".to_string()),
+            n: 4,
+            ..options("dall-e-2")
+        };
+        assert!(small.validate().is_ok());
+        let wide = ImageOptions {
+            size: Some("1792x1024".to_string()),
+            ..options("dall-e-2")
+        };
+        assert_eq!(
+            wide.validate().unwrap_err(),
+            "dall-e-2 only supports these sizes: 1024x1024, 512x512, 256x256."
+        );
+        let styled = ImageOptions {
+            style: Some("vivid".to_string()),
+            ..options("dall-e-2")
+        };
+        assert_eq!(
+            styled.validate().unwrap_err(),
+            "dall-e-2 doesn't support choosing the style."
+        );
+    }
+
+    #[test]
+    fn test_validate_edit() {
+        assert!(options("dall-e-2").validate_edit().is_ok());
+        assert_eq!(
+            options("dall-e-3").validate_edit().unwrap_err(),
+            "dall-e-3 can't work from an image, try dall-e-2."
+        );
+    }
+
+    #[test]
+    fn test_edit_model_from() {
+        static EDITOR: ImageModel = ImageModel {
+            name: "editor",
+            sizes: &["1024x1024"],
+            qualities: &[],
+            styles: &[],
+            max_images: 1,
+            edits: true,
+        };
+        let dall_e_2 = get_image_model("dall-e-2").unwrap();
+        let dall_e_3 = get_image_model("dall-e-3").unwrap();
+        assert_eq!(edit_model_from(&[dall_e_3, &EDITOR]), &EDITOR);
+        assert_eq!(edit_model_from(&[dall_e_3]), dall_e_2);
+        assert_eq!(edit_model_from(&[]), dall_e_2);
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            options("dall-e-3").describe(),
+            "dall-e-3 · 1024x1024 · standard · vivid"
+        );
+        let options = ImageOptions {
+            size: Some("512x512".to_string()),
+            n: 3,
+            ..options("dall-e-2")
+        };
+        assert_eq!(options.describe(), "dall-e-2 · 512x512 · 3 images");
+    }
+}
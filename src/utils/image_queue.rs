@@ -0,0 +1,302 @@
+use serenity::{model::channel::Message, prelude::TypeMapKey};
+use std::{
+    collections::VecDeque,
+    env,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+use tracing::error;
+
+/// Images drawn at the same time when `IMAGE_CONCURRENCY` isn't set.
+const DEFAULT_CONCURRENCY: usize = 2;
+/// Drawings that can wait for their turn when `IMAGE_QUEUE_SIZE` isn't set.
+const DEFAULT_QUEUE_SIZE: usize = 20;
+
+/// What a deferred response shows while its drawing waits.
+fn queued_message(position: usize) -> String {
+    format!("Queued, position {}", position)
+}
+
+/// Why a drawing didn't get its turn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueError {
+    Full,
+    /// The bot is shutting down.
+    Closed,
+}
+
+impl QueueError {
+    pub fn friendly_message(&self) -> &'static str {
+        match self {
+            QueueError::Full => {
+                "Too many images are being drawn right now, please try again in a bit."
+            }
+            QueueError::Closed => "The bot is restarting, please try again shortly.",
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_id: u64,
+    /// Tickets waiting for their turn, in the order they'll get it.
+    waiting: VecDeque<u64>,
+    /// Tickets waiting or drawing.
+    pending: usize,
+}
+
+/// Limits how many images are drawn at once across the bot, so a burst of
+/// `/imagine`s waits its turn instead of running into OpenAI's rate limits.
+pub struct JobQueue {
+    semaphore: Semaphore,
+    capacity: usize,
+    state: Mutex<QueueState>,
+    /// Pinged whenever a ticket joins, starts or leaves.
+    changed: watch::Sender<()>,
+}
+
+/// A place in the [`JobQueue`], held until the drawing is done.
+pub struct Ticket {
+    queue: Arc<JobQueue>,
+    id: u64,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.waiting.retain(|&id| id != self.id);
+        state.pending -= 1;
+        drop(state);
+        self.queue.changed.send_replace(());
+    }
+}
+
+impl Ticket {
+    /// 1-based place among the waiting tickets, `None` once it started.
+    fn position(&self) -> Option<usize> {
+        let state = self.queue.state.lock().unwrap();
+        state
+            .waiting
+            .iter()
+            .position(|&id| id == self.id)
+            .map(|index| index + 1)
+    }
+
+    /// Waits until the drawing may start. `on_position` is called with its
+    /// place whenever that changes while waiting, but not if it can start
+    /// right away.
+    pub async fn wait_turn<F, Fut>(
+        &self,
+        mut on_position: F,
+    ) -> Result<SemaphorePermit<'_>, QueueError>
+    where
+        F: FnMut(usize) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let queue = &self.queue;
+        let mut changed = queue.changed.subscribe();
+        // The semaphore hands out permits first come first served, as long
+        // as the same acquire is kept waiting
+        let acquire = queue.semaphore.acquire();
+        tokio::pin!(acquire);
+        let mut reported = None;
+        let mut is_stale = true;
+        loop {
+            tokio::select! {
+                biased;
+                permit = &mut acquire => {
+                    queue
+                        .state
+                        .lock()
+                        .unwrap()
+                        .waiting
+                        .retain(|&id| id != self.id);
+                    queue.changed.send_replace(());
+                    return permit.map_err(|_| QueueError::Closed);
+                }
+                _ = std::future::ready(()), if is_stale => {
+                    is_stale = false;
+                    let position = self.position();
+                    if position != reported {
+                        if let Some(position) = position {
+                            on_position(position).await;
+                        }
+                        reported = position;
+                    }
+                }
+                _ = changed.changed() => is_stale = true,
+            }
+        }
+    }
+}
+
+impl JobQueue {
+    pub fn new(concurrency: usize, capacity: usize) -> Self {
+        JobQueue {
+            semaphore: Semaphore::new(concurrency.max(1)),
+            capacity,
+            state: Mutex::new(QueueState::default()),
+            changed: watch::channel(()).0,
+        }
+    }
+
+    /// Reads the limits from `IMAGE_CONCURRENCY` and `IMAGE_QUEUE_SIZE`.
+    pub fn from_env() -> Self {
+        let get = |name: &str, default: usize| {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+        JobQueue::new(
+            get("IMAGE_CONCURRENCY", DEFAULT_CONCURRENCY),
+            get("IMAGE_QUEUE_SIZE", DEFAULT_QUEUE_SIZE),
+        )
+    }
+
+    /// Joins the back of the queue, unless it's full or closed.
+    pub fn enqueue(self: &Arc<Self>) -> Result<Ticket, QueueError> {
+        if self.semaphore.is_closed() {
+            return Err(QueueError::Closed);
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.waiting.len() >= self.capacity {
+            return Err(QueueError::Full);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiting.push_back(id);
+        state.pending += 1;
+        drop(state);
+        self.changed.send_replace(());
+        Ok(Ticket {
+            queue: self.clone(),
+            id,
+        })
+    }
+
+    /// Turns new drawings away and cancels the waiting ones. Drawings
+    /// already running are left to finish.
+    pub fn close(&self) {
+        self.semaphore.close();
+    }
+
+    /// Waits up to `timeout` for every ticket to be done with, so cancelled
+    /// drawings can say so before the bot goes away.
+    pub async fn wait_idle(&self, timeout: Duration) {
+        let mut changed = self.changed.subscribe();
+        let _ = tokio::time::timeout(timeout, async {
+            while self.state.lock().unwrap().pending > 0 {
+                if changed.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+}
+
+/// Joins `queue` and waits for the drawing's turn, showing its place in the
+/// queue through `edit`, which edits the deferred response. Call it right
+/// before drawing so the place shown matches the order turns are given in.
+/// The ticket is left in `slot` for the caller to hold until its response
+/// is done, which shutting down waits for.
+pub async fn take_turn<'a, F, Fut>(
+    queue: &Arc<JobQueue>,
+    slot: &'a mut Option<Ticket>,
+    mut edit: F,
+) -> Result<SemaphorePermit<'a>, QueueError>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = serenity::Result<Message>>,
+{
+    let ticket: &'a Ticket = slot.insert(queue.enqueue()?);
+    ticket
+        .wait_turn(|position| {
+            let edited = edit(queued_message(position));
+            async move {
+                if let Err(why) = edited.await {
+                    error!("Cannot show queue position: {}", why);
+                }
+            }
+        })
+        .await
+}
+
+/// The bot wide [`JobQueue`] image generation goes through.
+pub struct ImageQueue;
+
+impl TypeMapKey for ImageQueue {
+    type Value = Arc<JobQueue>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn report(ticket: &Ticket) -> Vec<usize> {
+        let mut positions = vec![];
+        tokio::select! {
+            _ = ticket.wait_turn(|position| {
+                positions.push(position);
+                async {}
+            }) => {}
+            _ = tokio::time::sleep(Duration::from_millis(20)) => {}
+        }
+        positions
+    }
+
+    #[tokio::test]
+    async fn test_positions() {
+        let queue = Arc::new(JobQueue::new(1, 3));
+        let first = queue.enqueue().unwrap();
+        let second = queue.enqueue().unwrap();
+        let third = queue.enqueue().unwrap();
+        assert_eq!(queue.enqueue().err(), Some(QueueError::Full));
+
+        let mut started = vec![];
+        let permit = first.wait_turn(|position| {
+            started.push(position);
+            async {}
+        });
+        let permit = permit.await.unwrap();
+        assert!(started.is_empty());
+        assert_eq!(first.position(), None);
+
+        assert_eq!(report(&third).await, vec![2]);
+        drop(permit);
+        drop(first);
+        let permit = second.wait_turn(|_| async {}).await.unwrap();
+        assert_eq!(third.position(), Some(1));
+        drop(permit);
+        drop(second);
+        assert!(third.wait_turn(|_| async {}).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let queue = Arc::new(JobQueue::new(1, 5));
+        let running = queue.enqueue().unwrap();
+        let permit = running.wait_turn(|_| async {}).await.unwrap();
+        let waiting = queue.enqueue().unwrap();
+
+        queue.close();
+        assert_eq!(
+            waiting.wait_turn(|_| async {}).await.err(),
+            Some(QueueError::Closed)
+        );
+        assert_eq!(queue.enqueue().err(), Some(QueueError::Closed));
+
+        drop(waiting);
+        drop(permit);
+        drop(running);
+        tokio::time::timeout(
+            Duration::from_millis(100),
+            queue.wait_idle(Duration::from_secs(10)),
+        )
+        .await
+        .expect("no tickets are left");
+    }
+}
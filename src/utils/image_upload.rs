@@ -0,0 +1,168 @@
+use serenity::{builder::CreateEmbed, model::channel::AttachmentType};
+
+use crate::utils::openai::GeneratedImage;
+
+/// Upload limit of a message in a server without boosts.
+pub const MAX_UPLOAD_BYTES: usize = 10 * 1024 * 1024;
+/// Most files, and embeds, a message can carry.
+pub const MAX_FILES_PER_MESSAGE: usize = 10;
+
+pub const EMBED_TITLE_LIMIT: usize = 256;
+const EMBED_DESCRIPTION_LIMIT: usize = 4096;
+
+/// Cuts `text` down to `limit` characters, marking where it was cut.
+pub fn truncate(text: &str, limit: usize) -> String {
+    if text.chars().count() <= limit {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(limit - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+fn filename(index: usize) -> String {
+    format!("imagine-{}.png", index + 1)
+}
+
+/// Which of the drawn images an uploaded file is.
+pub fn image_index(filename: &str) -> Option<usize> {
+    let number: usize = filename
+        .strip_prefix("imagine-")?
+        .strip_suffix(".png")?
+        .parse()
+        .ok()?;
+    number.checked_sub(1)
+}
+
+/// Groups images, by index, into messages that stay within Discord's upload
+/// limits, keeping their order. Images too large to upload at all are
+/// returned separately.
+fn pack_uploads(sizes: &[usize]) -> (Vec<Vec<usize>>, Vec<usize>) {
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    let mut too_large = Vec::new();
+    let mut batch_bytes = 0;
+    for (index, &size) in sizes.iter().enumerate() {
+        if size > MAX_UPLOAD_BYTES {
+            too_large.push(index);
+            continue;
+        }
+        match batches.last_mut() {
+            Some(batch)
+                if batch.len() < MAX_FILES_PER_MESSAGE
+                    && batch_bytes + size <= MAX_UPLOAD_BYTES =>
+            {
+                batch.push(index);
+                batch_bytes += size;
+            }
+            _ => {
+                batches.push(vec![index]);
+                batch_bytes = size;
+            }
+        }
+    }
+    (batches, too_large)
+}
+
+/// Shows the uploaded image `index` with the prompt it was drawn from, and
+/// the one the model rewrote it into, if any.
+fn image_embed(index: usize, prompt: &str, image: &GeneratedImage, settings: &str) -> CreateEmbed {
+    let mut embed = CreateEmbed::default();
+    embed
+        .title(truncate(prompt, EMBED_TITLE_LIMIT))
+        .image(format!("attachment://{}", filename(index)))
+        .footer(|footer| footer.text(settings));
+    if let Some(revised_prompt) = &image.revised_prompt {
+        embed.description(truncate(revised_prompt, EMBED_DESCRIPTION_LIMIT));
+    }
+    embed
+}
+
+fn image_attachment(index: usize, image: &GeneratedImage) -> AttachmentType<'static> {
+    AttachmentType::Bytes {
+        data: image.data.clone().into(),
+        filename: filename(index),
+    }
+}
+
+/// Files and embeds of one message.
+pub type Upload = (Vec<AttachmentType<'static>>, Vec<CreateEmbed>);
+
+/// Splits `images` into messages to upload, see [`pack_uploads`]. Also
+/// returns the numbers of the images that are too large to send.
+pub fn build_uploads(
+    images: &[GeneratedImage],
+    prompt: &str,
+    settings: &str,
+) -> (Vec<Upload>, Vec<usize>) {
+    let sizes: Vec<usize> = images.iter().map(|image| image.data.len()).collect();
+    let (batches, too_large) = pack_uploads(&sizes);
+    let uploads = batches
+        .into_iter()
+        .map(|batch| {
+            batch
+                .into_iter()
+                .map(|index| {
+                    let image = &images[index];
+                    (
+                        image_attachment(index, image),
+                        image_embed(index, prompt, image, settings),
+                    )
+                })
+                .unzip()
+        })
+        .collect();
+    (
+        uploads,
+        too_large.into_iter().map(|index| index + 1).collect(),
+    )
+}
+
+/// Tells which images couldn't be uploaded, by their numbers.
+pub fn too_large_message(too_large: &[usize]) -> Option<String> {
+    if too_large.is_empty() {
+        return None;
+    }
+    let numbers: Vec<String> = too_large.iter().map(usize::to_string).collect();
+    Some(format!(
+        "Image {} came out too large to upload.",
+        numbers.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: usize = 1024 * 1024;
+
+    #[test]
+    fn test_pack_uploads() {
+        assert_eq!(
+            pack_uploads(&[3 * MB, 3 * MB, 3 * MB, 3 * MB]),
+            (vec![vec![0, 1, 2], vec![3]], vec![])
+        );
+        assert_eq!(
+            pack_uploads(&[MB, 11 * MB, MB]),
+            (vec![vec![0, 2]], vec![1])
+        );
+        assert_eq!(
+            pack_uploads(&[1; 12]),
+            ((vec![(0..10).collect(), vec![10, 11]]), vec![])
+        );
+        assert_eq!(pack_uploads(&[]), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_image_index() {
+        assert_eq!(image_index(&filename(0)), Some(0));
+        assert_eq!(image_index("imagine-3.png"), Some(2));
+        assert_eq!(image_index("imagine-0.png"), None);
+        assert_eq!(image_index("cat.png"), None);
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("a longer prompt", 8), "a longe…");
+    }
+}
@@ -0,0 +1,156 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serenity::{
+    model::id::{ChannelId, GuildId, RoleId, UserId},
+    prelude::Context,
+};
+
+use crate::utils::chat_turn::ChatTurn;
+
+lazy_static! {
+    /// `<@id>`, `<@!id>`, `<@&id>`, `<#id>` and `<:name:id>` or `<a:name:id>`.
+    static ref MARKUP_RE: Regex =
+        Regex::new(r"<(@!?|@&|#)(\d+)>|<a?:(\w+):\d+>").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mention {
+    User(UserId),
+    Role(RoleId),
+    Channel(ChannelId),
+}
+
+/// Rewrites Discord markup in `content` into plain text for the model: the
+/// bot's own mention is dropped, other mentions become `@name` or `#name`
+/// through `resolve`, and custom emoji become `:name:`. Mentions `resolve`
+/// can't name are left as they are.
+pub fn normalize_content(
+    content: &str,
+    bot_id: UserId,
+    resolve: impl Fn(Mention) -> Option<String>,
+) -> String {
+    let normalized = MARKUP_RE.replace_all(content, |captures: &Captures| {
+        if let Some(emoji) = captures.get(3) {
+            return format!(":{}:", emoji.as_str());
+        }
+        let Ok(id) = captures[2].parse::<u64>() else {
+            return captures[0].to_string();
+        };
+        let (mention, prefix) = match &captures[1] {
+            "@&" => (Mention::Role(RoleId(id)), "@"),
+            "#" => (Mention::Channel(ChannelId(id)), "#"),
+            _ if id == bot_id.0 => return String::new(),
+            _ => (Mention::User(UserId(id)), "@"),
+        };
+        match resolve(mention) {
+            Some(name) => format!("{}{}", prefix, name),
+            None => captures[0].to_string(),
+        }
+    });
+    normalized.trim().to_string()
+}
+
+/// Names mentions with what the cache knows about them, preferring nicknames
+/// in `guild_id`. The cache only holds members, roles and channels thanks to
+/// the `GUILDS` and `GUILD_MEMBERS` intents.
+pub fn resolve_from_cache(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+) -> impl Fn(Mention) -> Option<String> + '_ {
+    move |mention| match mention {
+        Mention::User(user_id) => guild_id
+            .and_then(|guild_id| ctx.cache.member(guild_id, user_id))
+            .map(|member| member.display_name().to_string())
+            .or_else(|| ctx.cache.user(user_id).map(|user| user.name)),
+        Mention::Role(role_id) => guild_id
+            .and_then(|guild_id| ctx.cache.role(guild_id, role_id))
+            .map(|role| role.name),
+        Mention::Channel(channel_id) => ctx
+            .cache
+            .guild_channel(channel_id)
+            .map(|channel| channel.name),
+    }
+}
+
+/// Normalizes the content of every turn in `history`, see
+/// [`normalize_content`].
+pub fn normalize_turns(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+    bot_id: UserId,
+    history: &mut [ChatTurn],
+) {
+    let resolve = resolve_from_cache(ctx, guild_id);
+    for turn in history {
+        turn.content = normalize_content(&turn.content, bot_id, &resolve);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOT_ID: u64 = 1042057406525485096;
+
+    fn resolve(mention: Mention) -> Option<String> {
+        match mention {
+            Mention::User(UserId(10)) => Some("alice".to_string()),
+            Mention::Role(RoleId(20)) => Some("mods".to_string()),
+            Mention::Channel(ChannelId(30)) => Some("general".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_normalize_content_drops_bot_mention() {
+        assert_eq!(
+            normalize_content(
+                "<@1042057406525485096> hello there",
+                UserId(BOT_ID),
+                resolve
+            ),
+            "hello there"
+        );
+        assert_eq!(
+            normalize_content("hey <@!1042057406525485096>, sup", UserId(BOT_ID), resolve),
+            "hey , sup"
+        );
+    }
+
+    #[test]
+    fn test_normalize_content_resolves_mentions() {
+        assert_eq!(
+            normalize_content("ask <@10> or <@&20> in <#30>", UserId(BOT_ID), resolve),
+            "ask @alice or @mods in #general"
+        );
+    }
+
+    #[test]
+    fn test_normalize_content_keeps_unknown_mentions() {
+        // Someone the cache hasn't seen, next to someone it has
+        assert_eq!(
+            normalize_content("ask <@11> and <@10>", UserId(BOT_ID), resolve),
+            "ask <@11> and @alice"
+        );
+        assert_eq!(
+            normalize_content("<@!11> <@&21> <#31>", UserId(BOT_ID), resolve),
+            "<@!11> <@&21> <#31>"
+        );
+    }
+
+    #[test]
+    fn test_normalize_content_simplifies_emoji() {
+        assert_eq!(
+            normalize_content(
+                "nice <:pepe:456> <a:party_blob:789>",
+                UserId(BOT_ID),
+                resolve
+            ),
+            "nice :pepe: :party_blob:"
+        );
+        assert_eq!(
+            normalize_content("plain :smile: text", UserId(BOT_ID), resolve),
+            "plain :smile: text"
+        );
+    }
+}
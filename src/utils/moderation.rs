@@ -0,0 +1,574 @@
+use chrono::Utc;
+use lazy_static::lazy_static;
+use redis::{AsyncCommands, RedisResult};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serenity::{
+    async_trait,
+    model::id::{ChannelId, GuildId, UserId},
+};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+};
+use tracing::{error, warn};
+
+use crate::utils::{
+    image_upload::truncate,
+    openai::get_api_key,
+    openai_error::{send_with_retries, OpenAiError},
+    rate_limit::Feature,
+    redis_client::RedisManager,
+};
+
+/// Most rules a server can have, each one is tried on every prompt.
+pub const MAX_RULES: usize = 100;
+pub const MAX_PATTERN_LENGTH: u16 = 200;
+/// Newest blocked requests kept per server.
+const MAX_AUDIT_ENTRIES: isize = 200;
+/// How much of a blocked prompt the audit log keeps.
+const EXCERPT_LENGTH: usize = 200;
+/// Keeps a compiled rule from growing out of hand.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+lazy_static! {
+    /// Compiled blocklists by server.
+    static ref BLOCKLISTS: Mutex<HashMap<GuildId, Arc<Blocklist>>> = Mutex::new(HashMap::new());
+}
+
+/// What a moderator thinks of a prompt.
+#[derive(Debug, Default, PartialEq)]
+pub struct Verdict {
+    pub flagged: bool,
+    /// Categories it was flagged for.
+    pub categories: Vec<String>,
+}
+
+/// A service that screens prompts before they are sent to a model.
+#[async_trait]
+pub trait Moderator: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn check(&self, text: &str) -> Result<Verdict, OpenAiError>;
+}
+
+/// OpenAI's moderation endpoint, which doesn't count against usage.
+pub struct OpenAiModerator {
+    model: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ModerationRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: HashMap<String, bool>,
+}
+
+impl From<ModerationResponse> for Verdict {
+    fn from(response: ModerationResponse) -> Self {
+        let mut verdict = Verdict::default();
+        for result in response.results {
+            verdict.flagged |= result.flagged;
+            verdict.categories.extend(
+                result
+                    .categories
+                    .into_iter()
+                    .filter(|(_, flagged)| *flagged)
+                    .map(|(category, _)| category),
+            );
+        }
+        verdict.categories.sort();
+        verdict.categories.dedup();
+        verdict
+    }
+}
+
+#[async_trait]
+impl Moderator for OpenAiModerator {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn check(&self, text: &str) -> Result<Verdict, OpenAiError> {
+        let client = reqwest::Client::new();
+        let api_key = get_api_key();
+        let request = ModerationRequest {
+            model: &self.model,
+            input: text,
+        };
+        let response = send_with_retries(|| {
+            client
+                .post("https://api.openai.com/v1/moderations")
+                .header("Authorization", format!("Bearer {}", api_key))
+                .json(&request)
+        })
+        .await?;
+        let response: ModerationResponse = response
+            .json()
+            .await
+            .map_err(|e| OpenAiError::Parse(e.to_string()))?;
+        Ok(response.into())
+    }
+}
+
+/// Flags prompts containing any of a fixed list of words, ignoring case.
+/// Stands in for the moderation endpoint in tests and offline setups.
+pub struct LocalModerator {
+    terms: Vec<String>,
+}
+
+impl LocalModerator {
+    pub fn new(terms: &[&str]) -> Self {
+        LocalModerator {
+            terms: terms.iter().map(|term| term.to_lowercase()).collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl Moderator for LocalModerator {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn check(&self, text: &str) -> Result<Verdict, OpenAiError> {
+        let text = text.to_lowercase();
+        let flagged = self.terms.iter().any(|term| text.contains(term.as_str()));
+        Ok(Verdict {
+            flagged,
+            categories: if flagged {
+                vec!["local".to_string()]
+            } else {
+                vec![]
+            },
+        })
+    }
+}
+
+/// The moderator picked by `MODERATION`, if any:
+///
+/// - `openai`: the moderation endpoint, with the model from
+///   `MODERATION_MODEL`, `omni-moderation-latest` by default
+/// - `local`: [`LocalModerator`] with the comma separated
+///   `MODERATION_TERMS`
+pub fn from_env() -> Option<Box<dyn Moderator>> {
+    match env::var("MODERATION").ok()?.trim() {
+        "" | "0" | "false" | "off" => None,
+        "openai" => Some(Box::new(OpenAiModerator {
+            model: env::var("MODERATION_MODEL")
+                .unwrap_or_else(|_| "omni-moderation-latest".to_string()),
+        })),
+        "local" => {
+            let terms = env::var("MODERATION_TERMS").unwrap_or_default();
+            let terms: Vec<&str> = terms
+                .split(',')
+                .map(str::trim)
+                .filter(|term| !term.is_empty())
+                .collect();
+            Some(Box::new(LocalModerator::new(&terms)))
+        }
+        unknown => {
+            warn!("Unknown moderation service: {}", unknown);
+            None
+        }
+    }
+}
+
+/// A pattern a server doesn't want sent to the models.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "pattern", rename_all = "snake_case")]
+pub enum BlockRule {
+    /// Matches prompts containing it, ignoring case.
+    Keyword(String),
+    /// Matches prompts it finds a match in, ignoring case.
+    Regex(String),
+}
+
+impl BlockRule {
+    /// Checks the rule is usable, returning the message to show when it
+    /// isn't.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            BlockRule::Keyword(keyword) if keyword.trim().is_empty() => {
+                Err("The keyword can't be empty.".to_string())
+            }
+            BlockRule::Keyword(_) => Ok(()),
+            BlockRule::Regex(pattern) => compile(pattern)
+                .map(|_| ())
+                .map_err(|e| format!("That isn't a valid regex: {}", e)),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            BlockRule::Keyword(keyword) => format!("keyword `{}`", keyword),
+            BlockRule::Regex(pattern) => format!("regex `{}`", pattern),
+        }
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// A server's rules, with the regexes compiled once.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    rules: Vec<BlockRule>,
+    /// Compiled [`BlockRule::Regex`] rules by index, `None` for the others
+    /// and for ones that no longer compile.
+    regexes: Vec<Option<Regex>>,
+}
+
+impl Blocklist {
+    pub fn new(rules: Vec<BlockRule>) -> Self {
+        let regexes = rules
+            .iter()
+            .map(|rule| match rule {
+                BlockRule::Keyword(_) => None,
+                BlockRule::Regex(pattern) => compile(pattern)
+                    .map_err(|e| warn!("Skipping invalid block rule {}: {}", pattern, e))
+                    .ok(),
+            })
+            .collect();
+        Blocklist { rules, regexes }
+    }
+
+    /// The first rule that `text` matches.
+    fn find_match(&self, text: &str) -> Option<&BlockRule> {
+        let lowercase = text.to_lowercase();
+        self.rules
+            .iter()
+            .zip(&self.regexes)
+            .find(|(rule, regex)| match (rule, regex) {
+                (BlockRule::Keyword(keyword), _) => lowercase.contains(&keyword.to_lowercase()),
+                (BlockRule::Regex(_), regex) => {
+                    regex.as_ref().is_some_and(|regex| regex.is_match(text))
+                }
+            })
+            .map(|(rule, _)| rule)
+    }
+}
+
+/// Why a prompt was refused.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "by", rename_all = "snake_case")]
+pub enum BlockReason {
+    Blocklist { rule: BlockRule },
+    Moderation { categories: Vec<String> },
+}
+
+impl BlockReason {
+    /// What the person who sent the prompt is told.
+    pub fn refusal(&self) -> String {
+        match self {
+            BlockReason::Blocklist { .. } => {
+                "Sorry, I can't help with that, it's blocked in this server.".to_string()
+            }
+            BlockReason::Moderation { categories } if categories.is_empty() => {
+                "Sorry, I can't help with that, it was flagged by the content filter.".to_string()
+            }
+            BlockReason::Moderation { categories } => format!(
+                "Sorry, I can't help with that, it was flagged by the content filter ({}).",
+                categories.join(", ")
+            ),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            BlockReason::Blocklist { rule } => format!("blocklist {}", rule.describe()),
+            BlockReason::Moderation { categories } => {
+                format!("moderation ({})", categories.join(", "))
+            }
+        }
+    }
+}
+
+/// Tries the server's rules and then the moderator on `text`. Prompts are
+/// let through when the moderator can't be reached, so an outage doesn't
+/// take the bot down with it.
+pub async fn screen(
+    text: &str,
+    blocklist: &Blocklist,
+    moderator: Option<&dyn Moderator>,
+) -> Option<BlockReason> {
+    if let Some(rule) = blocklist.find_match(text) {
+        return Some(BlockReason::Blocklist { rule: rule.clone() });
+    }
+    let moderator = moderator?;
+    match moderator.check(text).await {
+        Ok(verdict) if verdict.flagged => Some(BlockReason::Moderation {
+            categories: verdict.categories,
+        }),
+        Ok(_) => None,
+        Err(e) => {
+            warn!(
+                moderator = moderator.name(),
+                error = %e,
+                "moderation check failed"
+            );
+            None
+        }
+    }
+}
+
+fn rules_key(guild_id: GuildId) -> String {
+    format!("blocklist_{}", guild_id)
+}
+
+fn audit_key(guild_id: Option<GuildId>) -> String {
+    match guild_id {
+        Some(guild_id) => format!("moderation_log_{}", guild_id),
+        None => "moderation_log_dm".to_string(),
+    }
+}
+
+pub async fn get_rules(conn: &mut RedisManager, guild_id: GuildId) -> RedisResult<Vec<BlockRule>> {
+    let values: Vec<String> = conn.lrange(rules_key(guild_id), 0, -1).await?;
+    Ok(values
+        .iter()
+        .filter_map(|value| match serde_json::from_str(value) {
+            Ok(rule) => Some(rule),
+            Err(e) => {
+                warn!("Skipping unreadable block rule: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// The server's [`Blocklist`], only compiled again once its rules changed,
+/// whichever instance of the bot changed them.
+async fn get_blocklist(conn: &mut RedisManager, guild_id: GuildId) -> RedisResult<Arc<Blocklist>> {
+    let rules = get_rules(conn, guild_id).await?;
+    let mut blocklists = BLOCKLISTS.lock().unwrap();
+    if let Some(blocklist) = blocklists.get(&guild_id) {
+        if blocklist.rules == rules {
+            return Ok(blocklist.clone());
+        }
+    }
+    let blocklist = Arc::new(Blocklist::new(rules));
+    blocklists.insert(guild_id, blocklist.clone());
+    Ok(blocklist)
+}
+
+/// Adds `rule` to the server's blocklist, returning false if it was
+/// already on it.
+pub async fn add_rule(
+    conn: &mut RedisManager,
+    guild_id: GuildId,
+    rule: &BlockRule,
+) -> RedisResult<bool> {
+    if get_rules(conn, guild_id).await?.contains(rule) {
+        return Ok(false);
+    }
+    let value = serde_json::to_string(rule).unwrap();
+    conn.rpush::<String, String, ()>(rules_key(guild_id), value)
+        .await?;
+    Ok(true)
+}
+
+/// Takes `rule` off the server's blocklist, returning false if it wasn't
+/// on it.
+pub async fn remove_rule(
+    conn: &mut RedisManager,
+    guild_id: GuildId,
+    rule: &BlockRule,
+) -> RedisResult<bool> {
+    let value = serde_json::to_string(rule).unwrap();
+    let removed: usize = conn.lrem(rules_key(guild_id), 0, value).await?;
+    Ok(removed > 0)
+}
+
+/// A refused prompt, kept for the server's admins.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    pub feature: String,
+    pub reason: BlockReason,
+    /// The start of the prompt.
+    pub excerpt: String,
+    /// Unix timestamp in seconds.
+    pub timestamp: i64,
+}
+
+async fn record_block(
+    conn: &mut RedisManager,
+    guild_id: Option<GuildId>,
+    entry: &AuditEntry,
+) -> RedisResult<()> {
+    let key = audit_key(guild_id);
+    redis::pipe()
+        .lpush(&key, serde_json::to_string(entry).unwrap())
+        .ignore()
+        .ltrim(&key, 0, MAX_AUDIT_ENTRIES - 1)
+        .ignore()
+        .query_async(conn)
+        .await
+}
+
+/// The newest `count` refused prompts in a server, newest first.
+pub async fn get_audit_log(
+    conn: &mut RedisManager,
+    guild_id: GuildId,
+    count: isize,
+) -> RedisResult<Vec<AuditEntry>> {
+    let values: Vec<String> = conn.lrange(audit_key(Some(guild_id)), 0, count - 1).await?;
+    Ok(values
+        .iter()
+        .filter_map(|value| serde_json::from_str(value).ok())
+        .collect())
+}
+
+/// Who sent a prompt and where, for [`check_prompt`].
+pub struct PromptSource {
+    pub user_id: UserId,
+    pub guild_id: Option<GuildId>,
+    pub channel_id: ChannelId,
+    pub feature: Feature,
+}
+
+/// Screens `text` before it goes to a model, see [`screen`]. Refused prompts
+/// are added to the audit log, and the message to answer with is returned.
+pub async fn check_prompt(
+    conn: &mut RedisManager,
+    source: &PromptSource,
+    text: &str,
+) -> Result<(), String> {
+    let blocklist = match source.guild_id {
+        Some(guild_id) => get_blocklist(conn, guild_id).await.unwrap_or_else(|e| {
+            error!("Failed to load blocklist: {}", e);
+            Default::default()
+        }),
+        None => Default::default(),
+    };
+    let moderator = from_env();
+    let Some(reason) = screen(text, &blocklist, moderator.as_deref()).await else {
+        return Ok(());
+    };
+
+    warn!(
+        user_id = %source.user_id,
+        channel_id = %source.channel_id,
+        feature = source.feature.name(),
+        reason = %reason.describe(),
+        "prompt blocked"
+    );
+    let refusal = reason.refusal();
+    let entry = AuditEntry {
+        user_id: source.user_id,
+        channel_id: source.channel_id,
+        feature: source.feature.name().to_string(),
+        reason,
+        excerpt: truncate(text, EXCERPT_LENGTH),
+        timestamp: Utc::now().timestamp(),
+    };
+    if let Err(e) = record_block(conn, source.guild_id, &entry).await {
+        error!("Failed to record blocked prompt: {}", e);
+    }
+    Err(refusal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_match() {
+        let blocklist = Blocklist::new(vec![
+            BlockRule::Keyword("Crypto".to_string()),
+            BlockRule::Regex(r"\bnft\d*\b".to_string()),
+        ]);
+        assert_eq!(
+            blocklist.find_match("Buy CRYPTO now"),
+            Some(&BlockRule::Keyword("Crypto".to_string()))
+        );
+        assert_eq!(
+            blocklist.find_match("mint an NFT2 today"),
+            Some(&BlockRule::Regex(r"\bnft\d*\b".to_string()))
+        );
+        assert_eq!(blocklist.find_match("nfts are fine"), None);
+
+        let broken = Blocklist::new(vec![BlockRule::Regex("(".to_string())]);
+        assert_eq!(broken.find_match("("), None);
+    }
+
+    #[test]
+    fn test_validate_rule() {
+        assert!(BlockRule::Regex(r"\d+".to_string()).validate().is_ok());
+        assert!(BlockRule::Regex("(".to_string()).validate().is_err());
+        assert!(BlockRule::Keyword(" ".to_string()).validate().is_err());
+    }
+
+    #[test]
+    fn test_rule_serialization() {
+        let rule = BlockRule::Regex("a+".to_string());
+        let json = serde_json::to_string(&rule).unwrap();
+        assert_eq!(json, r#"{"kind":"regex","pattern":"a+"}"#);
+        assert_eq!(serde_json::from_str::<BlockRule>(&json).unwrap(), rule);
+    }
+
+    #[test]
+    fn test_verdict_from_response() {
+        let response: ModerationResponse = serde_json::from_str(
+            r#"{
+                "id": "modr-1",
+                "model": "omni-moderation-latest",
+                "results": [{
+                    "flagged": true,
+                    "categories": {"violence": true, "harassment": false, "hate": true},
+                    "category_scores": {"violence": 0.9, "harassment": 0.1, "hate": 0.8}
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Verdict::from(response),
+            Verdict {
+                flagged: true,
+                categories: vec!["hate".to_string(), "violence".to_string()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_screen() {
+        let rule = BlockRule::Keyword("spoiler".to_string());
+        let rules = Blocklist::new(vec![rule.clone()]);
+        let moderator = LocalModerator::new(&["gore"]);
+
+        assert_eq!(
+            screen("no Spoilers please", &rules, Some(&moderator)).await,
+            Some(BlockReason::Blocklist { rule })
+        );
+        let reason = screen("lots of GORE", &rules, Some(&moderator))
+            .await
+            .unwrap();
+        assert_eq!(
+            reason.refusal(),
+            "Sorry, I can't help with that, it was flagged by the content filter (local)."
+        );
+        assert_eq!(screen("a cat", &rules, Some(&moderator)).await, None);
+        assert_eq!(
+            screen("lots of gore", &Blocklist::default(), None).await,
+            None
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use std::env;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use crate::utils::chat_provider::ChatProvider;
+use crate::utils::openai::{
+    drain_lines, ChatGPTRequest, ChatGPTResponse, Choice, FunctionCall, Message, ToolCall,
+    ToolDefinition, Usage,
+};
+use crate::utils::openai_error::{with_retries, OpenAiError};
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OllamaMessage {
+    role: String,
+    #[serde(default)]
+    content: String,
+    /// Base64 encoded images, for multimodal models.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+/// Unlike OpenAI, Ollama passes tool arguments as a JSON object.
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    arguments: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaOptions {
+    temperature: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: OllamaOptions,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct OllamaChatResponse {
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    done_reason: Option<String>,
+    #[serde(default)]
+    prompt_eval_count: u32,
+    #[serde(default)]
+    eval_count: u32,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Ollama's native `/api/chat` endpoint, for running fully offline.
+pub struct OllamaProvider {
+    base_url: String,
+    model_id: Option<String>,
+}
+
+impl OllamaProvider {
+    pub fn from_env() -> Self {
+        OllamaProvider {
+            base_url: env::var("OLLAMA_BASE_URL")
+                .unwrap_or_else(|_| "http://localhost:11434".to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            model_id: env::var("OLLAMA_MODEL_ID").ok(),
+        }
+    }
+}
+
+/// Ollama has no `name` field, so speakers are folded into the content.
+/// Images are only passed on when they are inlined as `data:` URLs.
+fn to_ollama_request(request: ChatGPTRequest, model_id: Option<&String>) -> OllamaChatRequest {
+    OllamaChatRequest {
+        model: model_id.cloned().unwrap_or(request.model),
+        messages: request
+            .messages
+            .into_iter()
+            .map(|message| OllamaMessage {
+                images: message
+                    .content
+                    .image_urls()
+                    .into_iter()
+                    .filter_map(|url| Some(url.strip_prefix("data:")?.split_once(";base64,")?.1))
+                    .map(str::to_string)
+                    .collect(),
+                content: match message.name {
+                    Some(name) => format!("{}: {}", name, message.content.text()),
+                    None => message.content.text(),
+                },
+                role: message.role,
+                tool_calls: message
+                    .tool_calls
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|tool_call| OllamaToolCall {
+                        function: OllamaFunctionCall {
+                            arguments: serde_json::from_str(&tool_call.function.arguments)
+                                .unwrap_or_default(),
+                            name: tool_call.function.name,
+                        },
+                    })
+                    .collect(),
+            })
+            .collect(),
+        stream: request.stream,
+        options: OllamaOptions {
+            temperature: request.temperature,
+        },
+        tools: request.tools,
+    }
+}
+
+/// Ollama doesn't give tool calls an id, so we number them for the results.
+fn to_tool_calls(tool_calls: Vec<OllamaToolCall>) -> Option<Vec<ToolCall>> {
+    if tool_calls.is_empty() {
+        return None;
+    }
+
+    Some(
+        tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(index, tool_call)| ToolCall {
+                id: format!("call_{}", index),
+                call_type: "function".to_string(),
+                function: FunctionCall {
+                    name: tool_call.function.name,
+                    arguments: tool_call.function.arguments.to_string(),
+                },
+            })
+            .collect(),
+    )
+}
+
+fn to_chat_response(
+    response: OllamaChatResponse,
+    content: String,
+    tool_calls: Vec<OllamaToolCall>,
+) -> ChatGPTResponse {
+    ChatGPTResponse {
+        object: "chat.completion".to_string(),
+        model: response.model,
+        choices: vec![Choice {
+            message: Message {
+                role: "assistant".to_string(),
+                content: content.into(),
+                tool_calls: to_tool_calls(tool_calls),
+                ..Default::default()
+            },
+            finish_reason: response.done_reason.unwrap_or_else(|| "stop".to_string()),
+            ..Default::default()
+        }],
+        usage: Usage {
+            prompt_tokens: response.prompt_eval_count,
+            completion_tokens: response.eval_count,
+            total_tokens: response.prompt_eval_count + response.eval_count,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+fn parse_line(line: &str) -> Result<OllamaChatResponse, OpenAiError> {
+    let response: OllamaChatResponse = serde_json::from_str(line).map_err(|e| {
+        warn!(?e, warning = "Error parsing response from Ollama",);
+        OpenAiError::Parse(e.to_string())
+    })?;
+    match response.error {
+        Some(error) => Err(OpenAiError::InvalidRequest(error)),
+        None => Ok(response),
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OllamaProvider {
+    fn name(&self) -> &str {
+        "ollama"
+    }
+
+    async fn complete(
+        &self,
+        request: ChatGPTRequest,
+        sender: Option<&mpsc::UnboundedSender<String>>,
+    ) -> Result<ChatGPTResponse, OpenAiError> {
+        let request = to_ollama_request(request, self.model_id.as_ref());
+        let client = reqwest::Client::new();
+        let url = format!("{}/api/chat", self.base_url);
+        debug!(target:"ollama", request = ?request, "prompt");
+        // Ollama reports its errors in the body, only retry when it can't be
+        // reached or is overloaded.
+        let response = with_retries(|| async {
+            let response = client.post(&url).json(&request).send().await.map_err(|e| {
+                warn!(?e, warning = "Error getting response from Ollama",);
+                OpenAiError::Transport(e.to_string())
+            })?;
+            if response.status().is_server_error() {
+                return Err(OpenAiError::ServerError {
+                    status: response.status().as_u16(),
+                    message: response.text().await.unwrap_or_default(),
+                });
+            }
+            Ok(response)
+        })
+        .await?;
+
+        if !request.stream {
+            let response_text = response.text().await.map_err(|e| {
+                warn!(?e, warning = "Error reading response from Ollama",);
+                OpenAiError::Transport(e.to_string())
+            })?;
+            let mut response_obj = parse_line(&response_text)?;
+            let message = response_obj.message.take().unwrap_or_default();
+            debug!(target:"ollama", response = ?response_obj, "response");
+            return Ok(to_chat_response(
+                response_obj,
+                message.content,
+                message.tool_calls,
+            ));
+        }
+
+        // Streamed answers arrive as one JSON object per line, the last one
+        // has `done` set and carries the token counts.
+        let mut body = response.bytes_stream();
+        let mut buffer = Vec::new();
+        let mut content = String::new();
+        let mut tool_calls = vec![];
+        while let Some(bytes) = body.next().await {
+            let bytes = bytes.map_err(|e| {
+                warn!(?e, warning = "Error reading stream from Ollama",);
+                OpenAiError::Transport(e.to_string())
+            })?;
+            buffer.extend_from_slice(&bytes);
+
+            for line in drain_lines(&mut buffer) {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut chunk = parse_line(line.trim())?;
+                if let Some(message) = chunk.message.take() {
+                    if let Some(sender) = sender.filter(|_| !message.content.is_empty()) {
+                        let _ = sender.send(message.content.clone());
+                    }
+                    content.push_str(&message.content);
+                    tool_calls.extend(message.tool_calls);
+                }
+                if chunk.done {
+                    debug!(target:"ollama", response = ?chunk, "response");
+                    return Ok(to_chat_response(chunk, content, tool_calls));
+                }
+            }
+        }
+
+        Err(OpenAiError::Transport(
+            "Ollama closed the stream before it was done".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::openai::{Content, ContentPart, ImageUrl};
+
+    #[test]
+    fn test_to_ollama_request_folds_names() {
+        let request = ChatGPTRequest {
+            model: "gpt-4o".to_string(),
+            messages: vec![
+                Message {
+                    role: "system".to_string(),
+                    content: "be nice".into(),
+                    ..Default::default()
+                },
+                Message {
+                    role: "user".to_string(),
+                    content: Content::Parts(vec![
+                        ContentPart::Text {
+                            text: "hi".to_string(),
+                        },
+                        ContentPart::ImageUrl {
+                            image_url: ImageUrl {
+                                url: "data:image/png;base64,AAAA".to_string(),
+                                detail: None,
+                            },
+                        },
+                    ]),
+                    name: Some("alice".to_string()),
+                    ..Default::default()
+                },
+            ],
+            temperature: 0.5,
+            stream: false,
+            stream_options: None,
+            tools: vec![],
+        };
+
+        let request = to_ollama_request(request, Some(&"llama3".to_string()));
+        assert_eq!(request.model, "llama3");
+        assert_eq!(request.messages[0].content, "be nice");
+        assert_eq!(request.messages[1].role, "user");
+        assert_eq!(request.messages[1].content, "alice: hi");
+        assert_eq!(request.messages[1].images, vec!["AAAA"]);
+        assert!(request.messages[0].images.is_empty());
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let line = r#"{"model":"llama3","message":{"role":"assistant","content":"Hi"},"done":true,"done_reason":"stop","prompt_eval_count":10,"eval_count":2}"#;
+        let mut response = parse_line(line).unwrap();
+        let message = response.message.take().unwrap();
+
+        let response = to_chat_response(response, message.content, message.tool_calls);
+        assert_eq!(response.choices[0].message.content, "Hi".into());
+        assert_eq!(response.choices[0].message.tool_calls, None);
+        assert_eq!(response.usage.total_tokens, 12);
+
+        let line = r#"{"model":"llama3","message":{"role":"assistant","content":"","tool_calls":[{"function":{"name":"set_reminder","arguments":{"minutes":5,"text":"tea"}}}]},"done":true}"#;
+        let mut response = parse_line(line).unwrap();
+        let message = response.message.take().unwrap();
+
+        let response = to_chat_response(response, message.content, message.tool_calls);
+        let tool_calls = response.choices[0].message.tool_calls.clone().unwrap();
+        assert_eq!(tool_calls[0].id, "call_0");
+        assert_eq!(tool_calls[0].function.name, "set_reminder");
+        let arguments: serde_json::Value =
+            serde_json::from_str(&tool_calls[0].function.arguments).unwrap();
+        assert_eq!(arguments["minutes"], 5);
+
+        let line = r#"{"error":"model 'llama3' not found"}"#;
+        assert_eq!(
+            parse_line(line).unwrap_err(),
+            OpenAiError::InvalidRequest("model 'llama3' not found".to_string())
+        );
+    }
+}
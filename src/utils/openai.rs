@@ -1,86 +1,279 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::NaiveDate;
+use futures::StreamExt;
+use reqwest::multipart::{Form, Part};
 use serde::{Deserialize, Serialize};
-use serenity::model::prelude::Message as DiscordMessage;
-use std::env;
+use serenity::async_trait;
+use serenity::model::{
+    id::{MessageId, UserId},
+    prelude::Message as DiscordMessage,
+};
+use serenity::prelude::Context;
+use std::{
+    collections::{HashMap, HashSet},
+    env, slice,
+};
+use tokio::sync::mpsc;
 use tracing::{debug, warn};
 
-use crate::handlers::chat::BOT_ID;
+use crate::tools::ToolRegistry;
+use crate::utils::chat_provider::{self, ChatProvider};
+use crate::utils::chat_turn::{ChatTurn, TurnAttachment, TurnRole};
+use crate::utils::image_options::ImageOptions;
+use crate::utils::mentions::normalize_turns;
+use crate::utils::openai_error::{send_with_retries, ApiErrorResponse, OpenAiError};
+use crate::utils::persona::PRESETS;
+use crate::utils::tokens::{TokenCounter, IMAGE_TOKENS};
+use crate::utils::usage::UsageRecord;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Message {
+    pub role: String,
+    /// `null` when the assistant only calls tools.
+    #[serde(default)]
+    #[serde(deserialize_with = "null_as_default")]
+    pub content: Content,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub refusal: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+/// Message content, either plain text or a list of parts when images are
+/// attached for vision models.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Content::Text(String::new())
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Content::Text(text.to_string())
+    }
+}
+
+impl Content {
+    /// The text of the content, with the parts' texts joined by newlines.
+    pub fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    pub fn image_urls(&self) -> Vec<&str> {
+        match self {
+            Content::Text(_) => vec![],
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+                    ContentPart::Text { .. } => None,
+                })
+                .collect(),
+        }
+    }
+
+    fn push_str(&mut self, delta: &str) {
+        match self {
+            Content::Text(text) => text.push_str(delta),
+            Content::Parts(parts) => parts.push(ContentPart::Text {
+                text: delta.to_string(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageUrl {
+    /// A web URL or a `data:` URL with the base64 encoded image.
+    pub url: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments, as generated by the model.
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolDefinition {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments object.
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatGPTRequest {
+    pub model: String,
+    pub messages: Vec<Message>,
+    pub temperature: f64,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Ask for a last chunk carrying the token usage of the whole answer.
+    pub include_usage: bool,
+}
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-struct Message {
-    role: String,
-    content: String,
+pub struct ChatGPTResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<Choice>,
+    pub usage: Usage,
     #[serde(default)]
-    refusal: Option<String>,
+    pub system_fingerprint: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ChatGPTRequest {
-    model: String,
-    messages: Vec<Message>,
-    temperature: f64,
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Choice {
+    pub index: u32,
+    pub message: Message,
+    #[serde(default)]
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: String,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-struct ChatGPTResponse {
+struct ChatGPTChunk {
+    #[serde(default)]
     id: String,
-    object: String,
+    #[serde(default)]
     created: i64,
+    #[serde(default)]
     model: String,
-    choices: Vec<Choice>,
-    usage: Usage,
     #[serde(default)]
-    system_fingerprint: Option<String>,
+    choices: Vec<ChunkChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-struct Choice {
+struct ChunkChoice {
     index: u32,
-    message: Message,
+    delta: Delta,
     #[serde(default)]
-    logprobs: Option<serde_json::Value>,
-    finish_reason: String,
+    finish_reason: Option<String>,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-struct Usage {
-    prompt_tokens: u32,
-    completion_tokens: u32,
-    total_tokens: u32,
+struct Delta {
     #[serde(default)]
-    prompt_tokens_details: Option<PromptTokensDetails>,
+    content: Option<String>,
     #[serde(default)]
-    completion_tokens_details: Option<CompletionTokensDetails>,
+    tool_calls: Vec<ToolCallDelta>,
 }
 
+/// Piece of a streamed tool call. The first one for an `index` carries the id
+/// and name, the following ones append to the arguments.
 #[derive(Debug, Default, Serialize, Deserialize)]
-struct PromptTokensDetails {
+struct ToolCallDelta {
+    index: usize,
     #[serde(default)]
-    cached_tokens: u32,
+    id: Option<String>,
+    #[serde(default)]
+    function: FunctionCallDelta,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-struct CompletionTokensDetails {
+struct FunctionCallDelta {
     #[serde(default)]
-    reasoning_tokens: u32,
+    name: Option<String>,
     #[serde(default)]
-    accepted_prediction_tokens: u32,
+    arguments: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
     #[serde(default)]
-    rejected_prediction_tokens: u32,
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default)]
+    pub completion_tokens_details: Option<CompletionTokensDetails>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OpenAIErrorResponse {
-    error: OpenAIError,
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct OpenAIError {
-    message: String,
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CompletionTokensDetails {
     #[serde(default)]
-    #[serde(rename = "type")]
-    error_type: String,
-    param: Option<String>,
-    code: Option<String>,
+    reasoning_tokens: u32,
+    #[serde(default)]
+    accepted_prediction_tokens: u32,
+    #[serde(default)]
+    rejected_prediction_tokens: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -90,12 +283,16 @@ struct ImageGenerationPayload {
     #[serde(default)]
     n: Option<u32>,
     #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     quality: Option<String>,
     #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     response_format: Option<String>,
     #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<String>,
     #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
     style: Option<String>,
 }
 
@@ -113,140 +310,1060 @@ struct ImageGenerationResponse {
     data: Vec<ImageResponse>,
 }
 
-const DEFAULT_PROMPT: &str = "Your are a helpful bot call 'washit'. You always give advice and opinion in best effort. Reply in full Cantonese for casual question; Full English if it is a serious question. Reply in japanese if the user is using japanese";
+/// A drawn image, downloaded so it outlives the hour OpenAI keeps it for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedImage {
+    /// PNG bytes.
+    pub data: Vec<u8>,
+    /// The prompt the model actually drew, if it rewrote ours.
+    pub revised_prompt: Option<String>,
+}
+
+/// Most images sent to vision models per request, newest first.
+const MAX_IMAGES: usize = 4;
+/// Largest image the OpenAI API accepts.
+const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Rounds of tool calls allowed before the model has to answer.
+const MAX_TOOL_ROUNDS: usize = 4;
+
+const SUMMARY_PROMPT: &str = "Summarise the conversation below for your own future reference. Keep names, facts, decisions and open questions, drop small talk. Fold in the previous summary if one is given. Answer with the summary only, in at most 200 words, in the language the conversation uses.";
+
+fn build_summary_message(summary: String) -> Message {
+    Message {
+        role: "system".to_string(),
+        content: format!("Summary of the earlier conversation: {}", summary).into(),
+        ..Default::default()
+    }
+}
 
-fn build_request(user_prompt: String, history_messages: Vec<Message>) -> ChatGPTRequest {
+/// Builds the request from the thread's `history_messages`, the last of which
+/// is the user turn being answered. The channel's `persona`, if any, replaces
+/// the default system prompt.
+fn build_request(
+    history_messages: Vec<Message>,
+    summary: Option<String>,
+    persona: Option<String>,
+    stream: bool,
+) -> ChatGPTRequest {
     let mut messages = vec![Message {
         role: "system".to_string(),
-        content: get_default_prompt(),
-        refusal: None,
+        content: persona.unwrap_or_else(get_default_prompt).into(),
+        ..Default::default()
     }];
 
+    messages.extend(summary.map(build_summary_message));
     messages.extend(history_messages);
 
-    messages.push(Message {
-        role: "user".to_string(),
-        content: user_prompt,
-        refusal: None,
-    });
-
     ChatGPTRequest {
         model: get_model_id(),
         messages,
         temperature: 1.0,
+        stream,
+        stream_options: None,
+        tools: vec![],
     }
 }
 
-async fn get_response(request: ChatGPTRequest, api_key: &str) -> Result<ChatGPTResponse, String> {
-    let client = reqwest::Client::new();
-    let url = "https://api.openai.com/v1/chat/completions";
-    debug!(target:"open_ai", request = ?request, "prompt");
-    let response = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| {
-            warn!(?e, warning = "Error getting response from OpenAI",);
-            format!("Error getting response from OpenAI: {:?}", e)
+/// Any backend speaking the OpenAI chat completions API: OpenAI itself or a
+/// compatible server such as LM Studio, vLLM or llama.cpp.
+pub struct OpenAiProvider {
+    name: String,
+    base_url: String,
+    api_key: Option<String>,
+    model_id: Option<String>,
+    /// Whether to ask for usage in streams, which some compatible servers
+    /// reject.
+    include_usage: bool,
+}
+
+impl OpenAiProvider {
+    pub fn openai() -> Self {
+        OpenAiProvider {
+            name: "openai".to_string(),
+            base_url: "https://api.openai.com/v1".to_string(),
+            api_key: Some(get_api_key()),
+            model_id: None,
+            include_usage: true,
+        }
+    }
+
+    pub fn compatible_from_env() -> Option<Self> {
+        let base_url = env::var("OPENAI_COMPATIBLE_BASE_URL").ok()?;
+        Some(OpenAiProvider {
+            name: "openai_compatible".to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: env::var("OPENAI_COMPATIBLE_KEY").ok(),
+            model_id: env::var("OPENAI_COMPATIBLE_MODEL_ID").ok(),
+            include_usage: env::var("OPENAI_COMPATIBLE_INCLUDE_USAGE")
+                .is_ok_and(|value| value == "1" || value == "true"),
+        })
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn complete(
+        &self,
+        mut request: ChatGPTRequest,
+        sender: Option<&mpsc::UnboundedSender<String>>,
+    ) -> Result<ChatGPTResponse, OpenAiError> {
+        if let Some(model_id) = &self.model_id {
+            request.model = model_id.clone();
+        }
+        if request.stream && self.include_usage {
+            request.stream_options = Some(StreamOptions {
+                include_usage: true,
+            });
+        }
+
+        let client = reqwest::Client::new();
+        let url = format!("{}/chat/completions", self.base_url);
+        debug!(target:"open_ai", provider = self.name, request = ?request, "prompt");
+        let response = send_with_retries(|| {
+            let request_builder = client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .json(&request);
+            match &self.api_key {
+                Some(api_key) => {
+                    request_builder.header("Authorization", format!("Bearer {}", api_key))
+                }
+                None => request_builder,
+            }
+        })
+        .await?;
+
+        if request.stream {
+            return read_stream(response, sender).await;
+        }
+
+        let response_text = response.text().await.map_err(|e| {
+            warn!(?e, warning = "Error reading response from OpenAI",);
+            OpenAiError::Transport(e.to_string())
         })?;
 
-    let response_text = response.text().await.map_err(|e| {
-        warn!(?e, warning = "Error reading response from OpenAI",);
-        format!("Error when reading response from OpenAI: {}", e)
-    })?;
+        let response_obj: ChatGPTResponse = serde_json::from_str(&response_text).map_err(|e| {
+            warn!(?e, warning = "Error parsing response from OpenAI",);
+            OpenAiError::Parse(e.to_string())
+        })?;
+        debug!(target:"open_ai", response = ?response_obj, "response");
 
-    if let Ok(error_response) = serde_json::from_str::<OpenAIErrorResponse>(&response_text) {
-        return Err(error_response.error.message);
+        Ok(response_obj)
     }
+}
 
-    let response_obj: ChatGPTResponse = serde_json::from_str(&response_text).map_err(|e| {
-        warn!(?e, warning = "Error parsing response from OpenAI",);
-        format!("Error when parsing response from OpenAI: {}", e)
-    })?;
-    debug!(target:"open_ai", response = ?response_obj, "response");
+/// Reads a `stream: true` completion, forwarding each content delta to
+/// `sender` and folding the chunks into a regular [`ChatGPTResponse`].
+async fn read_stream(
+    response: reqwest::Response,
+    sender: Option<&mpsc::UnboundedSender<String>>,
+) -> Result<ChatGPTResponse, OpenAiError> {
+    let mut response_obj = ChatGPTResponse {
+        object: "chat.completion".to_string(),
+        choices: vec![Choice {
+            message: Message {
+                role: "assistant".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    let mut body = response.bytes_stream();
+    let mut buffer = Vec::new();
+    while let Some(bytes) = body.next().await {
+        let bytes = bytes.map_err(|e| {
+            warn!(?e, warning = "Error reading stream from OpenAI",);
+            OpenAiError::Transport(e.to_string())
+        })?;
+        buffer.extend_from_slice(&bytes);
+
+        for data in drain_sse_data(&mut buffer) {
+            let Some(chunk) = parse_stream_data(&data)? else {
+                debug!(target:"open_ai", response = ?response_obj, "response");
+                return Ok(response_obj);
+            };
+
+            response_obj.id = chunk.id;
+            response_obj.created = chunk.created;
+            response_obj.model = chunk.model;
+            if let Some(usage) = chunk.usage {
+                response_obj.usage = usage;
+            }
+            for choice in chunk.choices {
+                if let Some(finish_reason) = choice.finish_reason {
+                    response_obj.choices[0].finish_reason = finish_reason;
+                }
+                merge_tool_call_deltas(
+                    &mut response_obj.choices[0].message,
+                    choice.delta.tool_calls,
+                );
+                let Some(delta) = choice.delta.content.filter(|delta| !delta.is_empty()) else {
+                    continue;
+                };
+                if let Some(sender) = sender {
+                    // The receiver going away only means nobody is watching
+                    // the progress, the full answer is still collected.
+                    let _ = sender.send(delta.clone());
+                }
+                response_obj.choices[0].message.content.push_str(&delta);
+            }
+        }
+    }
 
+    debug!(target:"open_ai", response = ?response_obj, "response");
     Ok(response_obj)
 }
 
-fn get_api_key() -> String {
+fn merge_tool_call_deltas(message: &mut Message, deltas: Vec<ToolCallDelta>) {
+    for delta in deltas {
+        let tool_calls = message.tool_calls.get_or_insert_with(Vec::new);
+        if tool_calls.len() <= delta.index {
+            tool_calls.resize_with(delta.index + 1, || ToolCall {
+                call_type: "function".to_string(),
+                ..Default::default()
+            });
+        }
+
+        let tool_call = &mut tool_calls[delta.index];
+        if let Some(id) = delta.id {
+            tool_call.id = id;
+        }
+        if let Some(name) = delta.function.name {
+            tool_call.function.name.push_str(&name);
+        }
+        if let Some(arguments) = delta.function.arguments {
+            tool_call.function.arguments.push_str(&arguments);
+        }
+    }
+}
+
+/// Takes the complete lines out of a streamed body, without their line
+/// endings. Bytes after the last newline are kept in `buffer` until the next
+/// chunk arrives, so characters split across chunks decode whole.
+pub fn drain_lines(buffer: &mut Vec<u8>) -> Vec<String> {
+    let mut lines = Vec::new();
+    while let Some(pos) = buffer.iter().position(|&byte| byte == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=pos).collect();
+        let line = String::from_utf8_lossy(&line);
+        lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+    lines
+}
+
+/// Splits a server-sent event body into `data:` payloads, see
+/// [`drain_lines`].
+fn drain_sse_data(buffer: &mut Vec<u8>) -> Vec<String> {
+    drain_lines(buffer)
+        .into_iter()
+        .filter_map(|line| Some(line.strip_prefix("data:")?.trim_start().to_string()))
+        .collect()
+}
+
+/// Parses a single stream payload, returning `None` once the terminating
+/// `[DONE]` marker is reached.
+fn parse_stream_data(data: &str) -> Result<Option<ChatGPTChunk>, OpenAiError> {
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+
+    // Errors after the headers were sent arrive with a 200 status, so only
+    // the body tells what went wrong.
+    if let Ok(error_response) = serde_json::from_str::<ApiErrorResponse>(data) {
+        return Err(OpenAiError::from_api_error(
+            reqwest::StatusCode::OK,
+            error_response.error,
+            None,
+        ));
+    }
+
+    serde_json::from_str(data).map(Some).map_err(|e| {
+        warn!(?e, warning = "Error parsing stream chunk from OpenAI",);
+        OpenAiError::Parse(e.to_string())
+    })
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+pub fn get_api_key() -> String {
     env::var("OPENAI_KEY").expect("OPENAI_KEY must be set")
 }
 
 fn get_default_prompt() -> String {
-    env::var("SYSTEM_PROMPT").unwrap_or_else(|_| DEFAULT_PROMPT.to_string())
+    env::var("SYSTEM_PROMPT").unwrap_or_else(|_| PRESETS[0].1.to_string())
 }
 
 fn get_model_id() -> String {
     env::var("MODEL_ID").unwrap_or_else(|_| "gpt-3.5-turbo".to_string())
 }
 
-fn build_history_messages(history: Vec<DiscordMessage>) -> Vec<Message> {
+/// Whether the configured model accepts images, overridable with
+/// `MODEL_VISION` for models we don't know about.
+fn supports_vision() -> bool {
+    if let Ok(value) = env::var("MODEL_VISION") {
+        return value == "1" || value == "true";
+    }
+
+    model_supports_vision(&get_model_id())
+}
+
+/// Whether `model_id` takes images. The reasoning models only do in their
+/// full versions, not `o1-mini` or `o1-preview`, so they have to match
+/// exactly or as a dated snapshot like `o1-2024-12-17`.
+fn model_supports_vision(model_id: &str) -> bool {
+    const VISION_FAMILIES: &[&str] = &["gpt-4o", "gpt-4.1", "gpt-4-turbo"];
+    const VISION_MODELS: &[&str] = &["o1", "o3"];
+    VISION_FAMILIES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+        || VISION_MODELS.iter().any(|model| {
+            model_id.strip_prefix(model).is_some_and(|rest| {
+                rest.is_empty()
+                    || rest
+                        .strip_prefix('-')
+                        .is_some_and(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok())
+            })
+        })
+}
+
+fn is_image(attachment: &TurnAttachment) -> bool {
+    attachment.size <= MAX_IMAGE_BYTES
+        && matches!(
+            attachment.content_type.as_deref(),
+            Some("image/png" | "image/jpeg" | "image/gif" | "image/webp")
+        )
+}
+
+/// The image attachments of user turns to send along, newest first and at
+/// most [`MAX_IMAGES`] of them.
+fn select_images(history: &[ChatTurn]) -> Vec<(MessageId, &TurnAttachment)> {
     history
-        .into_iter()
-        .map(|message| Message {
-            role: {
-                if message.id == BOT_ID {
-                    "assistant".to_string()
-                } else {
-                    "user".to_string()
-                }
-            },
-            content: message.content,
-            refusal: None,
+        .iter()
+        .rev()
+        .filter(|turn| turn.role == TurnRole::User)
+        .flat_map(|turn| {
+            turn.attachments
+                .iter()
+                .rev()
+                .filter(|attachment| is_image(attachment))
+                .map(|attachment| (turn.message_id, attachment))
         })
+        .take(MAX_IMAGES)
         .collect()
 }
 
-pub async fn ask_chat_gpt(user_prompt: String, history: Vec<DiscordMessage>) -> String {
-    let history_message = build_history_messages(history);
-    let request = build_request(user_prompt, history_message);
-    let api_key = get_api_key();
+async fn download(client: &reqwest::Client, url: &str) -> reqwest::Result<Vec<u8>> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Downloads the selected images as `data:` URLs. Discord's attachment links
+/// expire, so the model can't be relied on to fetch older ones itself.
+async fn fetch_images(history: &[ChatTurn]) -> HashMap<MessageId, Vec<String>> {
+    let client = reqwest::Client::new();
+    let mut images: HashMap<MessageId, Vec<String>> = HashMap::new();
+    for (message_id, attachment) in select_images(history).into_iter().rev() {
+        match download(&client, &attachment.url).await {
+            Ok(bytes) => images.entry(message_id).or_default().push(format!(
+                "data:{};base64,{}",
+                attachment.content_type.as_deref().unwrap_or("image/png"),
+                BASE64.encode(bytes)
+            )),
+            Err(e) => warn!(?e, url = attachment.url, "Error downloading image"),
+        }
+    }
+    images
+}
+
+/// Name the OpenAI `name` field accepts for `display_name`, which only
+/// allows up to 64 ASCII letters, digits, `_` and `-`.
+fn to_speaker_name(display_name: &str) -> Option<String> {
+    let name = display_name.trim().replace(' ', "_");
+    let is_valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    is_valid.then_some(name)
+}
+
+/// Converts a thread into chat messages. Assistant turns are preceded by the
+/// `tool_messages` that led to them, user turns get their `images` attached.
+/// When more than one person takes part, user turns carry the speaker in
+/// `name`, or as a content prefix if their display name can't be used there.
+fn build_history_messages(
+    history: Vec<ChatTurn>,
+    tool_messages: &HashMap<MessageId, Vec<Message>>,
+    images: &HashMap<MessageId, Vec<String>>,
+) -> Vec<Message> {
+    let speakers: HashSet<_> = history
+        .iter()
+        .filter(|turn| turn.role == TurnRole::User)
+        .map(|turn| turn.author_id)
+        .collect();
+    let is_group = speakers.len() > 1;
+
+    let mut messages = Vec::with_capacity(history.len());
+    for turn in history {
+        if turn.role == TurnRole::Assistant {
+            if let Some(tool_messages) = tool_messages.get(&turn.message_id) {
+                messages.extend(tool_messages.iter().cloned());
+            }
+            messages.push(Message {
+                role: "assistant".to_string(),
+                content: turn.content.into(),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let (name, text) = match to_speaker_name(&turn.display_name) {
+            Some(name) if is_group => (Some(name), turn.content),
+            None if is_group => (None, format!("{}: {}", turn.display_name, turn.content)),
+            _ => (None, turn.content),
+        };
+        let content = match images.get(&turn.message_id) {
+            Some(urls) if !urls.is_empty() => Content::Parts(
+                std::iter::once(ContentPart::Text { text })
+                    .chain(urls.iter().map(|url| ContentPart::ImageUrl {
+                        image_url: ImageUrl {
+                            url: url.clone(),
+                            detail: None,
+                        },
+                    }))
+                    .collect(),
+            ),
+            _ => Content::Text(text),
+        };
+        messages.push(Message {
+            role: "user".to_string(),
+            content,
+            name,
+            ..Default::default()
+        });
+    }
+    messages
+}
+
+/// Number of oldest `history` turns that have to be left out for the request
+/// to fit the configured model's token budget. The system prompt or
+/// `persona`, `summary` and `new_message` are always kept. The bot's turns
+/// count along with the `tool_messages` sent before them.
+pub fn count_overflowing_turns(
+    new_message: &ChatTurn,
+    history: &[ChatTurn],
+    tool_messages: &HashMap<MessageId, Vec<Message>>,
+    summary: Option<&str>,
+    persona: Option<&str>,
+) -> usize {
+    let counter = TokenCounter::from_env(&get_model_id());
+    let is_vision = supports_vision();
+    let count_tool_message = |message: &Message| {
+        let calls = message.tool_calls.iter().flatten().map(|tool_call| {
+            counter.count(&tool_call.function.name) + counter.count(&tool_call.function.arguments)
+        });
+        counter.count(&message.content.text()) + calls.sum::<usize>()
+    };
+    let count_turn = |turn: &ChatTurn| {
+        let images = if is_vision {
+            turn.attachments.iter().filter(|a| is_image(a)).count()
+        } else {
+            0
+        };
+        let tools = match turn.role {
+            TurnRole::Assistant => tool_messages
+                .get(&turn.message_id)
+                .map_or(0, |messages| messages.iter().map(count_tool_message).sum()),
+            TurnRole::User => 0,
+        };
+        counter.count(&turn.content) + images * IMAGE_TOKENS + tools
+    };
+
+    let system_prompt = persona.map_or_else(get_default_prompt, str::to_string);
+    let fixed = counter.count(&system_prompt)
+        + count_turn(new_message)
+        + summary.map_or(0, |summary| {
+            counter.count(&build_summary_message(summary.to_string()).content.text())
+        });
+    let turns: Vec<usize> = history.iter().map(count_turn).collect();
+
+    counter.overflowing_turns(fixed, &turns)
+}
+
+/// Compresses `turns` dropped from a thread, together with the `previous`
+/// summary of even older turns, into a new rolling summary. Returns the
+/// tokens it took alongside.
+pub async fn summarise_history(
+    previous: Option<String>,
+    turns: Vec<ChatTurn>,
+) -> Result<(String, UsageRecord), OpenAiError> {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("Previous summary: {}\n\n", previous));
+    }
+    for message in build_history_messages(turns, &HashMap::new(), &HashMap::new()) {
+        let speaker = message.name.as_deref().unwrap_or(&message.role);
+        transcript.push_str(&format!("{}: {}\n", speaker, message.content.text()));
+    }
+
+    let request = ChatGPTRequest {
+        model: get_model_id(),
+        messages: vec![
+            Message {
+                role: "system".to_string(),
+                content: SUMMARY_PROMPT.into(),
+                ..Default::default()
+            },
+            Message {
+                role: "user".to_string(),
+                content: transcript.into(),
+                ..Default::default()
+            },
+        ],
+        temperature: 0.2,
+        stream: false,
+        stream_options: None,
+        tools: vec![],
+    };
+
+    let response = chat_provider::from_env().complete(request, None).await?;
+    let choice = response
+        .choices
+        .first()
+        .ok_or_else(|| OpenAiError::Parse("response has no choices".to_string()))?;
+    Ok((
+        choice.message.content.text(),
+        UsageRecord::from_response(&response),
+    ))
+}
+
+/// A chat thread as stored by the chat handler, oldest turn first, with its
+/// content already normalized.
+pub struct Conversation {
+    pub history: Vec<ChatTurn>,
+    /// Tool calls and results that led to a bot turn, keyed by that turn.
+    pub tool_messages: HashMap<MessageId, Vec<Message>>,
+    pub summary: Option<String>,
+    /// System prompt set for the channel or guild with `/persona`.
+    pub persona: Option<String>,
+    pub bot_id: UserId,
+}
+
+pub struct ChatAnswer {
+    pub content: String,
+    /// Tool calls made while answering and their results, in order.
+    pub tool_messages: Vec<Message>,
+    /// Tokens spent on each completion it took.
+    pub usage: Vec<UsageRecord>,
+}
+
+/// Asks the chat model for a reply to `new_message`, running the tools it
+/// calls on the way. Mentions and emoji in the new message are turned into
+/// plain names first, like the handler does for the history, so the model
+/// doesn't echo raw markup back. When `sender` is
+/// given the completion is streamed and every content delta is forwarded to
+/// it as it arrives; the sender is dropped once the full answer is returned.
+pub async fn ask_chat_gpt(
+    ctx: &Context,
+    new_message: &DiscordMessage,
+    conversation: Conversation,
+    sender: Option<mpsc::UnboundedSender<String>>,
+) -> ChatAnswer {
+    let Conversation {
+        mut history,
+        tool_messages,
+        summary,
+        persona,
+        bot_id,
+    } = conversation;
+    let mut turn = ChatTurn::from_message(new_message, bot_id);
+    normalize_turns(
+        ctx,
+        new_message.guild_id,
+        bot_id,
+        slice::from_mut(&mut turn),
+    );
+    history.push(turn);
+    let images = if supports_vision() {
+        fetch_images(&history).await
+    } else {
+        HashMap::new()
+    };
+    let history_message = build_history_messages(history, &tool_messages, &images);
+    let mut request = build_request(history_message, summary, persona, sender.is_some());
+
+    let provider = chat_provider::from_env();
+    let registry = ToolRegistry::from_env();
+    request.tools = registry.definitions();
+
+    let mut answer = ChatAnswer {
+        content: String::new(),
+        tool_messages: vec![],
+        usage: vec![],
+    };
+    for round in 0..=MAX_TOOL_ROUNDS {
+        if round == MAX_TOOL_ROUNDS {
+            // Make the model answer with what it has got so far
+            request.tools.clear();
+        }
 
-    get_response(request, &api_key)
-        .await
-        .map(|response| response.choices[0].message.content.clone())
-        .unwrap_or_else(|e| format!("OpenAI: {}", e))
+        let message = match provider.complete(request.clone(), sender.as_ref()).await {
+            Ok(response) => {
+                answer.usage.push(UsageRecord::from_response(&response));
+                match response.choices.into_iter().next() {
+                    Some(choice) => choice.message,
+                    None => {
+                        let e = OpenAiError::Parse("response has no choices".to_string());
+                        warn!(error = %e, "chat completion failed");
+                        answer.content = e.friendly_message().to_string();
+                        return answer;
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "chat completion failed");
+                answer.content = e.friendly_message().to_string();
+                return answer;
+            }
+        };
+
+        let tool_calls = match &message.tool_calls {
+            Some(tool_calls) if !tool_calls.is_empty() => tool_calls.clone(),
+            _ => {
+                answer.content = message.content.text();
+                return answer;
+            }
+        };
+
+        request.messages.push(message.clone());
+        answer.tool_messages.push(message);
+        for tool_call in tool_calls {
+            let result = Message {
+                role: "tool".to_string(),
+                content: registry.call(ctx, new_message, &tool_call).await.into(),
+                tool_call_id: Some(tool_call.id),
+                ..Default::default()
+            };
+            request.messages.push(result.clone());
+            answer.tool_messages.push(result);
+        }
+    }
+
+    answer
 }
 
-pub async fn generate_images(prompt: &str) -> Result<Vec<String>, String> {
+pub async fn generate_images(
+    prompt: &str,
+    options: &ImageOptions,
+) -> Result<Vec<GeneratedImage>, OpenAiError> {
     let client = reqwest::Client::new();
     let api_key = get_api_key();
     let payload = ImageGenerationPayload {
-        model: "dall-e-3".to_string(),
+        model: options.model.clone(),
         prompt: prompt.to_string(),
-        n: Some(1),
-        quality: None,
-        response_format: None,
-        size: None,
-        style: None,
+        n: Some(options.n),
+        quality: options.quality.clone(),
+        response_format: Some("b64_json".to_string()),
+        size: options.size.clone(),
+        style: options.style.clone(),
     };
 
-    let response = client
-        .post("https://api.openai.com/v1/images/generations")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&payload)
-        .send()
-        .await
-        .map_err(|e| {
-            warn!(?e, warning = "Error getting response from OpenAI",);
-            format!("Error getting response from OpenAI: {}", e)
-        })?;
+    let response = send_with_retries(|| {
+        client
+            .post("https://api.openai.com/v1/images/generations")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .json(&payload)
+    })
+    .await?;
+    read_images(response).await
+}
 
+/// Form for the edit and variation endpoints, which take the source image as
+/// a file. Built anew for every attempt since forms can't be cloned.
+fn image_form(
+    prompt: Option<&str>,
+    image: &[u8],
+    mask: Option<&[u8]>,
+    options: &ImageOptions,
+) -> Form {
+    let png = |data: &[u8], file_name: &str| {
+        Part::bytes(data.to_vec())
+            .file_name(file_name.to_string())
+            .mime_str("image/png")
+            .expect("image/png is a valid MIME type")
+    };
+
+    let mut form = Form::new()
+        .text("model", options.model.clone())
+        .text("n", options.n.to_string())
+        .text("response_format", "b64_json")
+        .part("image", png(image, "image.png"));
+    if let Some(prompt) = prompt {
+        form = form.text("prompt", prompt.to_string());
+    }
+    if let Some(mask) = mask {
+        form = form.part("mask", png(mask, "mask.png"));
+    }
+    if let Some(size) = &options.size {
+        form = form.text("size", size.clone());
+    }
+    form
+}
+
+async fn send_image_form(
+    url: &str,
+    prompt: Option<&str>,
+    image: &[u8],
+    mask: Option<&[u8]>,
+    options: &ImageOptions,
+) -> Result<Vec<GeneratedImage>, OpenAiError> {
+    let client = reqwest::Client::new();
+    let api_key = get_api_key();
+    let response = send_with_retries(|| {
+        client
+            .post(url)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .multipart(image_form(prompt, image, mask, options))
+    })
+    .await?;
+    read_images(response).await
+}
+
+/// Redraws the PNG `image` as `prompt` describes. With a `mask` only its
+/// transparent areas are redrawn, otherwise those of the image itself.
+pub async fn edit_images(
+    prompt: &str,
+    image: &[u8],
+    mask: Option<&[u8]>,
+    options: &ImageOptions,
+) -> Result<Vec<GeneratedImage>, OpenAiError> {
+    send_image_form(
+        "https://api.openai.com/v1/images/edits",
+        Some(prompt),
+        image,
+        mask,
+        options,
+    )
+    .await
+}
+
+/// Draws variations of the PNG `image`.
+pub async fn create_variations(
+    image: &[u8],
+    options: &ImageOptions,
+) -> Result<Vec<GeneratedImage>, OpenAiError> {
+    send_image_form(
+        "https://api.openai.com/v1/images/variations",
+        None,
+        image,
+        None,
+        options,
+    )
+    .await
+}
+
+async fn read_images(response: reqwest::Response) -> Result<Vec<GeneratedImage>, OpenAiError> {
     let api_response: ImageGenerationResponse = response.json().await.map_err(|e| {
         warn!(?e, warning = "Error parsing response from OpenAI",);
-        format!("Error parsing response from OpenAI: {}", e)
+        OpenAiError::Parse(e.to_string())
     })?;
-    debug!("OpenAI response: {:#?}", api_response);
-    
-    let image_urls = api_response
+    debug!("OpenAI response with {} images", api_response.data.len());
+
+    api_response
         .data
         .into_iter()
-        .filter_map(|image_response| image_response.url)
-        .collect();
+        .map(|image_response| {
+            let data = image_response
+                .b64_json
+                .ok_or_else(|| OpenAiError::Parse("image without b64_json".to_string()))?;
+            Ok(GeneratedImage {
+                data: BASE64
+                    .decode(data)
+                    .map_err(|e| OpenAiError::Parse(e.to_string()))?,
+                revised_prompt: image_response.revised_prompt,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::chat_turn::CHAT_TURN_VERSION;
+
+    const BOT_ID: u64 = 1042057406525485096;
+
+    fn turn(id: u64, author_id: u64, name: &str, content: &str) -> ChatTurn {
+        ChatTurn {
+            version: CHAT_TURN_VERSION,
+            message_id: MessageId(id),
+            author_id: UserId(author_id),
+            display_name: name.to_string(),
+            role: if author_id == BOT_ID {
+                TurnRole::Assistant
+            } else {
+                TurnRole::User
+            },
+            content: content.to_string(),
+            attachments: vec![],
+            follow_up_ids: vec![],
+            timestamp: 1697587200,
+        }
+    }
+
+    #[test]
+    fn test_build_history_messages_roles_from_author() {
+        let history = vec![
+            turn(BOT_ID, 10, "alice", "hi washit"),
+            turn(11, BOT_ID, "washit", "hello alice"),
+            turn(12, 10, "alice", "how are you"),
+        ];
+
+        let messages = build_history_messages(history, &HashMap::new(), &HashMap::new());
+        let roles: Vec<_> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "user"]);
+        assert!(messages.iter().all(|m| m.name.is_none()));
+        assert_eq!(messages[2].content, "how are you".into());
+    }
+
+    #[test]
+    fn test_build_history_messages_names_speakers_in_group() {
+        let history = vec![
+            turn(1, 10, "alice", "hi washit"),
+            turn(2, BOT_ID, "washit", "hello"),
+            turn(3, 20, "bob smith", "what did she say"),
+            turn(4, 30, "陳大文", "我都想知"),
+        ];
+
+        let messages = build_history_messages(history, &HashMap::new(), &HashMap::new());
+        assert_eq!(messages[0].name.as_deref(), Some("alice"));
+        assert_eq!(messages[1].role, "assistant");
+        assert_eq!(messages[1].name, None);
+        assert_eq!(messages[2].name.as_deref(), Some("bob_smith"));
+        assert_eq!(messages[2].content, "what did she say".into());
+        assert_eq!(messages[3].name, None);
+        assert_eq!(messages[3].content, "陳大文: 我都想知".into());
+    }
+
+    #[test]
+    fn test_build_history_messages_inserts_tool_messages() {
+        let history = vec![
+            turn(1, 10, "alice", "what's the table?"),
+            turn(2, BOT_ID, "washit", "Man City are top"),
+        ];
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            call_type: "function".to_string(),
+            function: FunctionCall {
+                name: "get_epl_standings".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let tool_messages = HashMap::from([(
+            MessageId(2),
+            vec![
+                Message {
+                    role: "assistant".to_string(),
+                    tool_calls: Some(vec![tool_call.clone()]),
+                    ..Default::default()
+                },
+                Message {
+                    role: "tool".to_string(),
+                    content: "1. Man City".into(),
+                    tool_call_id: Some("call_1".to_string()),
+                    ..Default::default()
+                },
+            ],
+        )]);
 
-    Ok(image_urls)
+        let messages = build_history_messages(history, &tool_messages, &HashMap::new());
+        let roles: Vec<_> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "tool", "assistant"]);
+        assert_eq!(messages[1].tool_calls, Some(vec![tool_call]));
+        assert_eq!(messages[3].content, "Man City are top".into());
+    }
+
+    fn attachment(id: u64, content_type: &str) -> TurnAttachment {
+        TurnAttachment {
+            url: format!("https://cdn.discordapp.com/{}", id),
+            filename: "image".to_string(),
+            content_type: Some(content_type.to_string()),
+            size: 1024,
+        }
+    }
+
+    #[test]
+    fn test_build_history_messages_attaches_images() {
+        let history = vec![
+            turn(1, 10, "alice", "what is this?"),
+            turn(2, BOT_ID, "washit", "a cat"),
+        ];
+        let images = HashMap::from([
+            (MessageId(1), vec!["data:image/png;base64,AAAA".to_string()]),
+            (MessageId(2), vec!["data:image/png;base64,BBBB".to_string()]),
+        ]);
+
+        let messages = build_history_messages(history, &HashMap::new(), &images);
+        assert_eq!(
+            messages[0].content,
+            Content::Parts(vec![
+                ContentPart::Text {
+                    text: "what is this?".to_string()
+                },
+                ContentPart::ImageUrl {
+                    image_url: ImageUrl {
+                        url: "data:image/png;base64,AAAA".to_string(),
+                        detail: None,
+                    }
+                },
+            ])
+        );
+        assert_eq!(messages[1].content, "a cat".into());
+
+        let value = serde_json::to_value(&messages[0]).unwrap();
+        assert_eq!(value["content"][0]["type"], "text");
+        assert_eq!(value["content"][1]["type"], "image_url");
+        assert_eq!(
+            value["content"][1]["image_url"]["url"],
+            "data:image/png;base64,AAAA"
+        );
+    }
+
+    #[test]
+    fn test_model_supports_vision() {
+        assert!(model_supports_vision("gpt-4o-mini"));
+        assert!(model_supports_vision("o1"));
+        assert!(model_supports_vision("o1-2024-12-17"));
+        assert!(model_supports_vision("o3"));
+        assert!(!model_supports_vision("o1-mini"));
+        assert!(!model_supports_vision("o1-mini-2024-09-12"));
+        assert!(!model_supports_vision("o1-preview"));
+        assert!(!model_supports_vision("o3-mini"));
+        assert!(!model_supports_vision("gpt-3.5-turbo"));
+    }
+
+    #[test]
+    fn test_select_images() {
+        let mut first = turn(1, 10, "alice", "look");
+        first.attachments = vec![attachment(11, "image/png"), attachment(12, "text/plain")];
+        let mut second = turn(2, BOT_ID, "washit", "nice");
+        second.attachments = vec![attachment(21, "image/png")];
+        let mut third = turn(3, 20, "bob", "and this");
+        third.attachments = (31..=35).map(|id| attachment(id, "image/jpeg")).collect();
+        let history = vec![first, second, third];
+
+        let selected: Vec<_> = select_images(&history)
+            .into_iter()
+            .map(|(message_id, attachment)| (message_id.0, attachment.url.as_str()))
+            .collect();
+        assert_eq!(
+            selected,
+            vec![
+                (3, "https://cdn.discordapp.com/35"),
+                (3, "https://cdn.discordapp.com/34"),
+                (3, "https://cdn.discordapp.com/33"),
+                (3, "https://cdn.discordapp.com/32"),
+            ]
+        );
+
+        let selected = select_images(&history[..2]);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].1.url, "https://cdn.discordapp.com/11");
+    }
+
+    #[test]
+    fn test_message_content_null() {
+        let data = r#"{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","function":{"name":"get_epl_standings","arguments":"{}"}}]}"#;
+        let message: Message = serde_json::from_str(data).unwrap();
+        assert_eq!(message.content, "".into());
+        assert_eq!(
+            message.tool_calls.unwrap()[0].function.name,
+            "get_epl_standings"
+        );
+    }
+
+    #[test]
+    fn test_merge_tool_call_deltas() {
+        let mut message = Message::default();
+        let chunks = [
+            r#"[{"index":0,"id":"call_1","type":"function","function":{"name":"set_reminder","arguments":""}}]"#,
+            r#"[{"index":0,"function":{"arguments":"{\"minutes\":"}}]"#,
+            r#"[{"index":0,"function":{"arguments":"5}"}}]"#,
+        ];
+        for chunk in chunks {
+            merge_tool_call_deltas(&mut message, serde_json::from_str(chunk).unwrap());
+        }
+
+        let tool_calls = message.tool_calls.unwrap();
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].id, "call_1");
+        assert_eq!(tool_calls[0].function.name, "set_reminder");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"minutes":5}"#);
+    }
+
+    #[test]
+    fn test_drain_sse_data_keeps_partial_line() {
+        let mut buffer = b"data: {\"a\":1}\n\ndata: [DO".to_vec();
+
+        let data = drain_sse_data(&mut buffer);
+        assert_eq!(data, vec!["{\"a\":1}"]);
+        assert_eq!(buffer, b"data: [DO");
+
+        buffer.extend_from_slice(b"NE]\r\n");
+        assert_eq!(drain_sse_data(&mut buffer), vec!["[DONE]"]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_drain_lines_keeps_split_characters() {
+        let line = "data: 你好\n".as_bytes();
+        // Cut in the middle of the three bytes of 你
+        let (first, second) = line.split_at(7);
+        let mut buffer = first.to_vec();
+        assert!(drain_lines(&mut buffer).is_empty());
+
+        buffer.extend_from_slice(second);
+        assert_eq!(drain_lines(&mut buffer), vec!["data: 你好"]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_parse_stream_data() {
+        let data = r#"{"id":"1","object":"chat.completion.chunk","created":1,"model":"gpt","choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}"#;
+        let chunk = parse_stream_data(data).unwrap().unwrap();
+        assert_eq!(chunk.id, "1");
+        assert_eq!(chunk.choices[0].delta.content.as_deref(), Some("Hello"));
+
+        let data = r#"{"choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#;
+        let chunk = parse_stream_data(data).unwrap().unwrap();
+        assert_eq!(chunk.choices[0].delta.content, None);
+        assert_eq!(chunk.choices[0].finish_reason.as_deref(), Some("stop"));
+
+        assert!(parse_stream_data("[DONE]").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_stream_data_error() {
+        let data = r#"{"error":{"message":"boom","type":"server_error","param":null,"code":null}}"#;
+        assert_eq!(
+            parse_stream_data(data).unwrap_err(),
+            OpenAiError::ServerError {
+                status: 500,
+                message: "boom".to_string()
+            }
+        );
+    }
 }
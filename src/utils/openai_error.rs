@@ -0,0 +1,357 @@
+use rand::Rng;
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{fmt, future::Future, time::Duration};
+use tracing::warn;
+
+/// Retries after the first attempt for rate limits, server and transport
+/// errors.
+const MAX_RETRIES: u32 = 3;
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(20);
+/// Longest we wait for, whatever `Retry-After` asks.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiErrorResponse {
+    pub error: ApiError,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiError {
+    pub message: String,
+    #[serde(default)]
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpenAiError {
+    /// The request never got an answer: DNS, TLS, connection reset, ...
+    Transport(String),
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    ServerError {
+        status: u16,
+        message: String,
+    },
+    /// The account is out of credits, retrying won't help.
+    QuotaExceeded(String),
+    /// The prompt or the answer was rejected by the safety system.
+    ContentPolicy(String),
+    InvalidRequest(String),
+    Parse(String),
+}
+
+impl fmt::Display for OpenAiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpenAiError::Transport(message) => write!(f, "transport error: {}", message),
+            OpenAiError::RateLimited { message, .. } => write!(f, "rate limited: {}", message),
+            OpenAiError::ServerError { status, message } => {
+                write!(f, "server error {}: {}", status, message)
+            }
+            OpenAiError::QuotaExceeded(message) => write!(f, "quota exceeded: {}", message),
+            OpenAiError::ContentPolicy(message) => write!(f, "content policy: {}", message),
+            OpenAiError::InvalidRequest(message) => write!(f, "invalid request: {}", message),
+            OpenAiError::Parse(message) => write!(f, "parse error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for OpenAiError {}
+
+impl OpenAiError {
+    /// Sorts an error answer by its status code, falling back to the error
+    /// code and type in the body for errors reported inside a stream.
+    pub fn from_api_error(
+        status: StatusCode,
+        error: ApiError,
+        retry_after: Option<Duration>,
+    ) -> Self {
+        let message = error.message;
+        match error.code.as_deref() {
+            Some("content_policy_violation") => return OpenAiError::ContentPolicy(message),
+            // Also sent with a 429, but no amount of waiting fixes it
+            Some("insufficient_quota") => return OpenAiError::QuotaExceeded(message),
+            _ => {}
+        }
+        if status == StatusCode::TOO_MANY_REQUESTS
+            || error.code.as_deref() == Some("rate_limit_exceeded")
+        {
+            return OpenAiError::RateLimited {
+                message,
+                retry_after,
+            };
+        }
+        if status.is_server_error() {
+            return OpenAiError::ServerError {
+                status: status.as_u16(),
+                message,
+            };
+        }
+        if error.error_type == "server_error" {
+            return OpenAiError::ServerError {
+                status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                message,
+            };
+        }
+        OpenAiError::InvalidRequest(message)
+    }
+
+    /// Sorts an unsuccessful answer, whether or not its body is JSON.
+    pub fn from_response(status: StatusCode, headers: &HeaderMap, body: &str) -> Self {
+        let error = match serde_json::from_str::<ApiErrorResponse>(body) {
+            Ok(error_response) => error_response.error,
+            Err(_) => ApiError {
+                message: format!("{} {}", status, body.trim()),
+                error_type: String::new(),
+                param: None,
+                code: None,
+            },
+        };
+        OpenAiError::from_api_error(status, error, parse_retry_after(headers))
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            OpenAiError::Transport(_)
+                | OpenAiError::RateLimited { .. }
+                | OpenAiError::ServerError { .. }
+        )
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            OpenAiError::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// What to tell the user in the channel.
+    pub fn friendly_message(&self) -> &'static str {
+        match self {
+            OpenAiError::Transport(_) => {
+                "I couldn't reach the AI service, please try again in a moment."
+            }
+            OpenAiError::RateLimited { .. } => {
+                "I'm getting too many requests right now, please try again in a minute."
+            }
+            OpenAiError::ServerError { .. } => {
+                "The AI service is having trouble right now, please try again later."
+            }
+            OpenAiError::QuotaExceeded(_) => {
+                "The AI service account is out of credits, please let an admin know."
+            }
+            OpenAiError::ContentPolicy(_) => {
+                "Sorry, that request was blocked by the content policy."
+            }
+            OpenAiError::InvalidRequest(_) => "Sorry, the AI service couldn't handle that request.",
+            OpenAiError::Parse(_) => "I got an answer I couldn't understand, please try again.",
+        }
+    }
+}
+
+/// Reads `retry-after-ms` or `retry-after` (in seconds). HTTP dates are not
+/// supported, we fall back to our own backoff for those.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name| headers.get(name)?.to_str().ok()?.trim().parse::<f64>().ok();
+    let retry_after = header("retry-after-ms")
+        .map(|millis| millis / 1000.0)
+        .or_else(|| header("retry-after"))?;
+    Duration::try_from_secs_f64(retry_after).ok()
+}
+
+/// Delay before retry number `retry` (starting at 0): exponential with
+/// jitter, unless the server told us how long to wait.
+fn backoff(retry: u32, retry_after: Option<Duration>) -> Duration {
+    if let Some(retry_after) = retry_after {
+        return retry_after.min(MAX_RETRY_AFTER);
+    }
+    let ceiling = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(retry))
+        .min(MAX_DELAY);
+    ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// Runs `attempt` until it succeeds, fails with an error that isn't worth
+/// retrying, or runs out of retries.
+pub async fn with_retries<T, F, Fut>(mut attempt: F) -> Result<T, OpenAiError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, OpenAiError>>,
+{
+    let mut retry = 0;
+    loop {
+        match attempt().await {
+            Err(e) if e.is_retryable() && retry < MAX_RETRIES => {
+                let delay = backoff(retry, e.retry_after());
+                warn!(error = %e, ?delay, retry, "OpenAI request failed, retrying");
+                tokio::time::sleep(delay).await;
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends the request built by `build`, retrying on failures worth retrying.
+/// Only successful answers are returned, the rest become an [`OpenAiError`].
+pub async fn send_with_retries<F>(build: F) -> Result<Response, OpenAiError>
+where
+    F: Fn() -> RequestBuilder,
+{
+    with_retries(|| async {
+        let response = build().send().await.map_err(|e| {
+            warn!(?e, warning = "Error getting response from OpenAI",);
+            OpenAiError::Transport(e.to_string())
+        })?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| OpenAiError::Transport(e.to_string()))?;
+        Err(OpenAiError::from_response(status, &headers, &body))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_from_response() {
+        let headers = HeaderMap::new();
+        let body = r#"{"error":{"message":"slow down","type":"requests","param":null,"code":"rate_limit_exceeded"}}"#;
+        assert!(matches!(
+            OpenAiError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, body),
+            OpenAiError::RateLimited { .. }
+        ));
+
+        // Errors inside a stream come with a 200
+        let error: ApiErrorResponse = serde_json::from_str(body).unwrap();
+        assert!(matches!(
+            OpenAiError::from_api_error(StatusCode::OK, error.error, None),
+            OpenAiError::RateLimited { .. }
+        ));
+
+        let body = r#"{"error":{"message":"no credits","type":"insufficient_quota","param":null,"code":"insufficient_quota"}}"#;
+        let error = OpenAiError::from_response(StatusCode::TOO_MANY_REQUESTS, &headers, body);
+        assert_eq!(error, OpenAiError::QuotaExceeded("no credits".to_string()));
+        assert!(!error.is_retryable());
+
+        let body = r#"{"error":{"message":"unsafe","type":"invalid_request_error","param":null,"code":"content_policy_violation"}}"#;
+        assert_eq!(
+            OpenAiError::from_response(StatusCode::BAD_REQUEST, &headers, body),
+            OpenAiError::ContentPolicy("unsafe".to_string())
+        );
+
+        let body = r#"{"error":{"message":"bad model","type":"invalid_request_error","param":"model","code":null}}"#;
+        assert_eq!(
+            OpenAiError::from_response(StatusCode::NOT_FOUND, &headers, body),
+            OpenAiError::InvalidRequest("bad model".to_string())
+        );
+
+        assert_eq!(
+            OpenAiError::from_response(StatusCode::BAD_GATEWAY, &headers, "<html>"),
+            OpenAiError::ServerError {
+                status: 502,
+                message: "502 Bad Gateway <html>".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after(&headers), None);
+
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::from_secs(2)));
+
+        headers.insert("retry-after-ms", HeaderValue::from_static("1500"));
+        assert_eq!(
+            parse_retry_after(&headers),
+            Some(Duration::from_millis(1500))
+        );
+
+        headers.remove("retry-after-ms");
+        headers.insert(
+            "retry-after",
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        for retry in 0..10 {
+            let delay = backoff(retry, None);
+            assert!(delay <= MAX_DELAY);
+            assert!(delay >= BASE_DELAY / 2);
+        }
+        assert_eq!(
+            backoff(0, Some(Duration::from_secs(3))),
+            Duration::from_secs(3)
+        );
+        assert_eq!(backoff(0, Some(Duration::from_secs(3600))), MAX_RETRY_AFTER);
+    }
+
+    fn rate_limited() -> OpenAiError {
+        OpenAiError::RateLimited {
+            message: "slow down".to_string(),
+            retry_after: Some(Duration::ZERO),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_retries_until_success() {
+        let attempts = Cell::new(0);
+        let result = with_retries(|| async {
+            attempts.set(attempts.get() + 1);
+            if attempts.get() < 3 {
+                Err(rate_limited())
+            } else {
+                Ok("done")
+            }
+        })
+        .await;
+
+        assert_eq!(result, Ok("done"));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_with_retries_gives_up() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retries(|| async {
+            attempts.set(attempts.get() + 1);
+            Err(rate_limited())
+        })
+        .await;
+        assert_eq!(result, Err(rate_limited()));
+        assert_eq!(attempts.get(), MAX_RETRIES + 1);
+
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retries(|| async {
+            attempts.set(attempts.get() + 1);
+            Err(OpenAiError::InvalidRequest("nope".to_string()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
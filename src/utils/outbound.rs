@@ -0,0 +1,92 @@
+use serenity::{
+    builder::CreateAllowedMentions,
+    http::CacheHttp,
+    model::{channel::Message, id::UserId},
+    Result,
+};
+use std::fmt::Display;
+
+/// Breaks `@everyone` and `@here` with a zero width space, so they show as
+/// typed but can't ping even where mentions aren't restricted.
+pub fn escape_mass_mentions(content: impl Display) -> String {
+    content
+        .to_string()
+        .replace("@everyone", "@\u{200B}everyone")
+        .replace("@here", "@\u{200B}here")
+}
+
+/// Lets the message ping `user_id` and nobody else: no roles, no
+/// `@everyone`, and no other users whatever the content says.
+pub fn only_user(
+    allowed_mentions: &mut CreateAllowedMentions,
+    user_id: UserId,
+) -> &mut CreateAllowedMentions {
+    allowed_mentions
+        .empty_parse()
+        .empty_roles()
+        .users([user_id])
+        .replied_user(true)
+}
+
+/// Replies to `message` with generated or user supplied `content`, only
+/// letting it mention the author.
+pub async fn reply_safely(
+    cache_http: impl CacheHttp,
+    message: &Message,
+    content: impl Display,
+) -> Result<Message> {
+    let content = escape_mass_mentions(content);
+    message
+        .channel_id
+        .send_message(cache_http.http(), |m| {
+            m.content(content)
+                .reference_message(message)
+                .allowed_mentions(|am| only_user(am, message.author.id))
+        })
+        .await
+}
+
+/// Replaces the content of `reply`, which was sent for `user_id`, the same
+/// way [`reply_safely`] sends it.
+pub async fn edit_safely(
+    cache_http: impl CacheHttp,
+    reply: &mut Message,
+    user_id: UserId,
+    content: impl Display,
+) -> Result<()> {
+    let content = escape_mass_mentions(content);
+    reply
+        .edit(cache_http, |m| {
+            m.content(content)
+                .allowed_mentions(|am| only_user(am, user_id))
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_escape_mass_mentions() {
+        assert_eq!(
+            escape_mass_mentions("hey @everyone and @here"),
+            "hey @\u{200B}everyone and @\u{200B}here"
+        );
+        assert_eq!(
+            escape_mass_mentions("mail me@example.com"),
+            "mail me@example.com"
+        );
+    }
+
+    #[test]
+    fn test_only_user() {
+        let mut allowed_mentions = CreateAllowedMentions::default();
+        only_user(&mut allowed_mentions, UserId(10));
+        assert_eq!(allowed_mentions.0["parse"], json!([]));
+        assert_eq!(allowed_mentions.0["roles"], json!([]));
+        assert_eq!(allowed_mentions.0["users"], json!(["10"]));
+        assert_eq!(allowed_mentions.0["replied_user"], json!(true));
+    }
+}
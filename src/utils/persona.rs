@@ -0,0 +1,104 @@
+use redis::{AsyncCommands, RedisResult};
+use serenity::model::id::{ChannelId, GuildId};
+
+use crate::utils::redis_client::RedisManager;
+
+/// Personas that ship with the bot. The first one is the default when
+/// neither `SYSTEM_PROMPT` nor a stored persona applies.
+pub const PRESETS: &[(&str, &str)] = &[
+    (
+        "washit",
+        "Your are a helpful bot call 'washit'. You always give advice and opinion in best effort. Reply in full Cantonese for casual question; Full English if it is a serious question. Reply in japanese if the user is using japanese",
+    ),
+    (
+        "assistant",
+        "You are a helpful assistant in a Discord server. Answer clearly and accurately, in the language the user writes in.",
+    ),
+    (
+        "concise",
+        "You are a helpful assistant in a Discord server. Keep every answer as short as possible, a sentence or two unless asked for more.",
+    ),
+];
+
+pub fn get_preset(name: &str) -> Option<&'static str> {
+    PRESETS
+        .iter()
+        .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+        .map(|(_, prompt)| *prompt)
+}
+
+/// Where a persona applies. A channel persona wins over the guild one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PersonaScope {
+    Guild(GuildId),
+    Channel(GuildId, ChannelId),
+}
+
+impl PersonaScope {
+    fn key(&self) -> String {
+        match self {
+            PersonaScope::Guild(guild_id) => format!("persona_{}", guild_id),
+            PersonaScope::Channel(guild_id, channel_id) => {
+                format!("persona_{}_{}", guild_id, channel_id)
+            }
+        }
+    }
+}
+
+/// Scopes that apply to a message, most specific first.
+fn scopes_for(guild_id: GuildId, channel_id: ChannelId) -> [PersonaScope; 2] {
+    [
+        PersonaScope::Channel(guild_id, channel_id),
+        PersonaScope::Guild(guild_id),
+    ]
+}
+
+pub async fn set_persona(
+    conn: &mut RedisManager,
+    scope: PersonaScope,
+    prompt: &str,
+) -> RedisResult<()> {
+    conn.set(scope.key(), prompt).await
+}
+
+pub async fn reset_persona(conn: &mut RedisManager, scope: PersonaScope) -> RedisResult<()> {
+    conn.del(scope.key()).await
+}
+
+/// The most specific persona stored for a channel, with the scope it was
+/// set at. Direct messages have no guild and always use the default.
+pub async fn get_persona(
+    conn: &mut RedisManager,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+) -> RedisResult<Option<(PersonaScope, String)>> {
+    let Some(guild_id) = guild_id else {
+        return Ok(None);
+    };
+
+    for scope in scopes_for(guild_id, channel_id) {
+        if let Some(prompt) = conn.get::<String, Option<String>>(scope.key()).await? {
+            return Ok(Some((scope, prompt)));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_preset() {
+        assert_eq!(get_preset("washit"), Some(PRESETS[0].1));
+        assert_eq!(get_preset("Concise"), Some(PRESETS[2].1));
+        assert_eq!(get_preset("pirate"), None);
+    }
+
+    #[test]
+    fn test_scopes_for() {
+        let scopes = scopes_for(GuildId(1), ChannelId(2));
+        assert_eq!(scopes[0].key(), "persona_1_2");
+        assert_eq!(scopes[1].key(), "persona_1");
+    }
+}
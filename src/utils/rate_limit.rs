@@ -0,0 +1,227 @@
+use chrono::Utc;
+use lazy_static::lazy_static;
+use redis::{RedisResult, Script};
+use serenity::model::id::{ChannelId, RoleId, UserId};
+use std::{env, time::Duration};
+
+use crate::utils::redis_client::RedisManager;
+
+lazy_static! {
+    /// Sliding window over sorted sets of request times, one per key. Either
+    /// every key has room and the request is counted in all of them, or
+    /// nothing is counted and the wait until the fullest one frees up a slot
+    /// is returned, in milliseconds.
+    static ref SLIDING_WINDOW: Script = Script::new(
+        r"
+        local now = tonumber(ARGV[1])
+        local wait = 0
+        for i, key in ipairs(KEYS) do
+            local window = tonumber(ARGV[i * 2 + 1])
+            local limit = tonumber(ARGV[i * 2 + 2])
+            redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window)
+            if redis.call('ZCARD', key) >= limit then
+                local oldest = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
+                wait = math.max(wait, tonumber(oldest[2]) + window - now)
+            end
+        end
+        if wait > 0 then
+            return wait
+        end
+        for i, key in ipairs(KEYS) do
+            redis.call('ZADD', key, now, ARGV[2])
+            redis.call('PEXPIRE', key, ARGV[i * 2 + 1])
+        end
+        return 0
+        "
+    );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub count: u32,
+    pub window: Duration,
+}
+
+impl Limit {
+    /// Parses `count/seconds`, e.g. `5/60` for five per minute. `0` turns
+    /// the limit off.
+    fn parse(value: &str) -> Option<Option<Self>> {
+        if value.trim() == "0" {
+            return Some(None);
+        }
+        let (count, seconds) = value.split_once('/')?;
+        let count = count.trim().parse().ok()?;
+        let seconds: u64 = seconds.trim().parse().ok()?;
+        if count == 0 || seconds == 0 {
+            return Some(None);
+        }
+        Some(Some(Limit {
+            count,
+            window: Duration::from_secs(seconds),
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    Chat,
+    Imagine,
+}
+
+impl Feature {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Feature::Chat => "chat",
+            Feature::Imagine => "imagine",
+        }
+    }
+
+    /// Per user and per channel limits, unless overridden by
+    /// `RATE_LIMIT_{FEATURE}_USER` and `RATE_LIMIT_{FEATURE}_CHANNEL`.
+    fn default_limits(&self) -> (Limit, Limit) {
+        let limit = |count, seconds| Limit {
+            count,
+            window: Duration::from_secs(seconds),
+        };
+        match self {
+            Feature::Chat => (limit(10, 60), limit(30, 60)),
+            Feature::Imagine => (limit(3, 300), limit(10, 300)),
+        }
+    }
+}
+
+fn get_limit(feature: Feature, scope: &str, default: Limit) -> Option<Limit> {
+    let var = format!(
+        "RATE_LIMIT_{}_{}",
+        feature.name().to_uppercase(),
+        scope.to_uppercase()
+    );
+    env::var(var)
+        .ok()
+        .and_then(|value| Limit::parse(&value))
+        .unwrap_or(Some(default))
+}
+
+fn get_limits(feature: Feature, user_id: UserId, channel_id: ChannelId) -> Vec<(String, Limit)> {
+    let (user_limit, channel_limit) = feature.default_limits();
+    [
+        ("user", user_id.0, get_limit(feature, "user", user_limit)),
+        (
+            "channel",
+            channel_id.0,
+            get_limit(feature, "channel", channel_limit),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(scope, id, limit)| {
+        Some((
+            format!("ratelimit_{}_{}_{}", feature.name(), scope, id),
+            limit?,
+        ))
+    })
+    .collect()
+}
+
+/// Members with the `RATE_LIMIT_EXEMPT_ROLE` role are never throttled.
+pub fn is_exempt(roles: &[RoleId]) -> bool {
+    env::var("RATE_LIMIT_EXEMPT_ROLE")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_some_and(|exempt_role| roles.iter().any(|role| role.0 == exempt_role))
+}
+
+/// Counts a use of `feature` by `user_id` in `channel_id`. Returns how long
+/// to wait when the user or the channel is over its limit, in which case
+/// the use isn't counted.
+pub async fn check_rate_limit(
+    conn: &mut RedisManager,
+    feature: Feature,
+    user_id: UserId,
+    channel_id: ChannelId,
+) -> RedisResult<Option<Duration>> {
+    let limits = get_limits(feature, user_id, channel_id);
+    if limits.is_empty() {
+        return Ok(None);
+    }
+
+    let now = Utc::now().timestamp_millis();
+    let mut invocation = SLIDING_WINDOW.prepare_invoke();
+    invocation
+        .arg(now)
+        .arg(format!("{}-{}", now, rand::random::<u32>()));
+    for (key, limit) in &limits {
+        invocation
+            .key(key)
+            .arg(limit.window.as_millis() as u64)
+            .arg(limit.count);
+    }
+
+    let wait: u64 = invocation.invoke_async(conn).await?;
+    Ok((wait > 0).then(|| Duration::from_millis(wait)))
+}
+
+/// Whether `user_id` should be told they are throttled on `feature`: only
+/// the first time until `wait` is over, so spamming the bot doesn't make it
+/// spam the channel back.
+pub async fn should_notify_throttle(
+    conn: &mut RedisManager,
+    feature: Feature,
+    user_id: UserId,
+    wait: Duration,
+) -> RedisResult<bool> {
+    let key = format!("ratelimit_notice_{}_{}", feature.name(), user_id);
+    let is_new: Option<String> = redis::cmd("SET")
+        .arg(key)
+        .arg(1)
+        .arg("NX")
+        .arg("PX")
+        .arg(wait.as_millis().max(1) as u64)
+        .query_async(conn)
+        .await?;
+    Ok(is_new.is_some())
+}
+
+/// Tells a throttled user when they can try again, as a Discord timestamp
+/// that counts down on its own.
+pub fn throttled_message(wait: Duration) -> String {
+    let retry_at = Utc::now().timestamp() + wait.as_secs() as i64 + 1;
+    format!("Slow down! You can try again <t:{}:R>.", retry_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_parse() {
+        assert_eq!(
+            Limit::parse("5/60"),
+            Some(Some(Limit {
+                count: 5,
+                window: Duration::from_secs(60)
+            }))
+        );
+        assert_eq!(Limit::parse("0"), Some(None));
+        assert_eq!(Limit::parse("0/60"), Some(None));
+        assert_eq!(Limit::parse("five"), None);
+        assert_eq!(Limit::parse("5/"), None);
+    }
+
+    #[test]
+    fn test_get_limits() {
+        let limits = get_limits(Feature::Imagine, UserId(1), ChannelId(2));
+        assert_eq!(
+            limits,
+            vec![
+                (
+                    "ratelimit_imagine_user_1".to_string(),
+                    Feature::Imagine.default_limits().0
+                ),
+                (
+                    "ratelimit_imagine_channel_2".to_string(),
+                    Feature::Imagine.default_limits().1
+                ),
+            ]
+        );
+    }
+}
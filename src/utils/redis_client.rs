@@ -1,7 +1,14 @@
+use redis::aio::ConnectionManager;
 use serenity::prelude::TypeMapKey;
 
+/// Async Redis connection shared by every handler. Commands are multiplexed
+/// over a single connection that reconnects in the background after it
+/// drops, so a Redis outage only fails the commands sent while it lasts.
+/// Cheap to clone.
+pub type RedisManager = ConnectionManager;
+
 pub struct RedisClient;
 
 impl TypeMapKey for RedisClient {
-    type Value = redis::Client;
+    type Value = RedisManager;
 }
@@ -0,0 +1,174 @@
+use chrono::Utc;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::{env, fmt::Display, future::Future};
+use tracing::{error, warn};
+
+use crate::libs::epl_data_client::{get_standings, StandingsResponse};
+use crate::utils::redis_client::RedisManager;
+
+const CACHE_KEY: &str = "epl_standings";
+/// Seconds cached standings are served without asking upstream when
+/// `EPL_CACHE_TTL` isn't set.
+const DEFAULT_TTL_SECONDS: i64 = 10 * 60;
+/// How long standings are kept to fall back on once they are out of date:
+/// 7 days.
+const RETENTION_SECONDS: usize = 60 * 60 * 24 * 7;
+
+/// Standings as stored in Redis, with when they were fetched.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct CachedStandings {
+    standings: StandingsResponse,
+    /// Unix timestamp in seconds.
+    fetched_at: i64,
+}
+
+/// Standings to show, and whether they could be brought up to date.
+#[derive(Debug, PartialEq)]
+pub enum Standings {
+    Fresh(StandingsResponse),
+    /// Upstream failed, these are the last ones fetched.
+    Stale {
+        standings: StandingsResponse,
+        fetched_at: i64,
+    },
+}
+
+fn get_ttl() -> i64 {
+    env::var("EPL_CACHE_TTL")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_TTL_SECONDS)
+}
+
+/// Picks the standings to show: `cached` while it's younger than `ttl`,
+/// otherwise whatever `fetch` brings, falling back to `cached` when that
+/// fails. The second value is what to cache, if anything changed.
+async fn resolve<E, Fut>(
+    cached: Option<CachedStandings>,
+    now: i64,
+    ttl: i64,
+    fetch: impl FnOnce() -> Fut,
+) -> (Result<Standings, E>, Option<CachedStandings>)
+where
+    E: Display,
+    Fut: Future<Output = Result<StandingsResponse, E>>,
+{
+    if let Some(cached) = &cached {
+        if now - cached.fetched_at < ttl {
+            return (Ok(Standings::Fresh(cached.standings.clone())), None);
+        }
+    }
+    match (fetch().await, cached) {
+        (Ok(standings), _) => {
+            let fresh = CachedStandings {
+                standings: standings.clone(),
+                fetched_at: now,
+            };
+            (Ok(Standings::Fresh(standings)), Some(fresh))
+        }
+        (Err(e), Some(cached)) => {
+            warn!("Failed to fetch standings, serving cached ones: {}", e);
+            let stale = Standings::Stale {
+                standings: cached.standings,
+                fetched_at: cached.fetched_at,
+            };
+            (Ok(stale), None)
+        }
+        (Err(e), None) => (Err(e), None),
+    }
+}
+
+/// The league standings, from Redis while they are fresh per
+/// `EPL_CACHE_TTL` (10 minutes by default), otherwise from upstream. When
+/// upstream fails the last fetched ones are served as [`Standings::Stale`].
+pub async fn get_cached_standings(conn: &mut RedisManager) -> Result<Standings, reqwest::Error> {
+    let cached = match conn.get::<_, Option<String>>(CACHE_KEY).await {
+        Ok(value) => value.and_then(|value| match serde_json::from_str(&value) {
+            Ok(cached) => Some(cached),
+            Err(e) => {
+                warn!("Ignoring unreadable cached standings: {}", e);
+                None
+            }
+        }),
+        Err(e) => {
+            error!("Failed to load cached standings: {}", e);
+            None
+        }
+    };
+
+    let (standings, update) =
+        resolve(cached, Utc::now().timestamp(), get_ttl(), get_standings).await;
+    if let Some(update) = update {
+        let value = serde_json::to_string(&update).unwrap();
+        if let Err(e) = conn
+            .set_ex::<_, _, ()>(CACHE_KEY, value, RETENTION_SECONDS)
+            .await
+        {
+            error!("Failed to cache standings: {}", e);
+        }
+    }
+    standings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn standings(updated_at: &str) -> StandingsResponse {
+        StandingsResponse {
+            standing: vec![],
+            updated_at: updated_at.to_string(),
+        }
+    }
+
+    fn cached(updated_at: &str, fetched_at: i64) -> Option<CachedStandings> {
+        Some(CachedStandings {
+            standings: standings(updated_at),
+            fetched_at,
+        })
+    }
+
+    async fn ok() -> Result<StandingsResponse, String> {
+        Ok(standings("new"))
+    }
+
+    async fn down() -> Result<StandingsResponse, String> {
+        Err("down".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_serves_fresh_cache() {
+        let (result, update) = resolve(cached("old", 1000), 1500, 600, down).await;
+        assert_eq!(result.unwrap(), Standings::Fresh(standings("old")));
+        assert_eq!(update, None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_refreshes_expired_cache() {
+        let (result, update) = resolve(cached("old", 1000), 1600, 600, ok).await;
+        assert_eq!(result.unwrap(), Standings::Fresh(standings("new")));
+        assert_eq!(update, cached("new", 1600));
+
+        let (result, update) = resolve(None, 1600, 600, ok).await;
+        assert_eq!(result.unwrap(), Standings::Fresh(standings("new")));
+        assert_eq!(update, cached("new", 1600));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_falls_back_to_stale() {
+        let (result, update) = resolve(cached("old", 1000), 5000, 600, down).await;
+        assert_eq!(
+            result.unwrap(),
+            Standings::Stale {
+                standings: standings("old"),
+                fetched_at: 1000,
+            }
+        );
+        assert_eq!(update, None);
+
+        let (result, update) = resolve(None, 5000, 600, down).await;
+        assert_eq!(result.unwrap_err(), "down");
+        assert_eq!(update, None);
+    }
+}
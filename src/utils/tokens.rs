@@ -0,0 +1,126 @@
+use std::env;
+
+/// Tokens added by the chat format around every message (role, separators).
+const MESSAGE_OVERHEAD: usize = 4;
+/// What an image costs vision models at high detail, roughly.
+pub const IMAGE_TOKENS: usize = 765;
+/// Tokens kept free for the model's answer when no override is configured.
+const DEFAULT_RESERVED_TOKENS: usize = 1024;
+
+/// Approximates how many tokens a model spends on a piece of text. We don't
+/// ship the real BPE tables, so this errs on the side of over-counting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenCounter {
+    context_window: usize,
+    reserved_tokens: usize,
+    ascii_chars_per_token: usize,
+}
+
+impl TokenCounter {
+    pub fn for_model(model_id: &str) -> Self {
+        // (model prefix, context window, ascii chars per token), most specific first
+        const MODELS: &[(&str, usize, usize)] = &[
+            ("gpt-4o", 128_000, 4),
+            ("gpt-4.1", 1_000_000, 4),
+            ("gpt-4-turbo", 128_000, 4),
+            ("gpt-4-32k", 32_768, 3),
+            ("gpt-4", 8_192, 3),
+            ("gpt-3.5-turbo", 16_385, 3),
+            ("o1", 128_000, 4),
+            ("o3", 200_000, 4),
+        ];
+
+        let (context_window, ascii_chars_per_token) = MODELS
+            .iter()
+            .find(|(prefix, _, _)| model_id.starts_with(prefix))
+            .map(|(_, window, chars)| (*window, *chars))
+            .unwrap_or((8_192, 3));
+
+        TokenCounter {
+            context_window,
+            reserved_tokens: DEFAULT_RESERVED_TOKENS,
+            ascii_chars_per_token,
+        }
+    }
+
+    /// Counter for `model_id`, honouring `MODEL_CONTEXT_WINDOW` and
+    /// `MODEL_RESERVED_TOKENS` overrides from the environment.
+    pub fn from_env(model_id: &str) -> Self {
+        let mut counter = Self::for_model(model_id);
+        if let Some(window) = env::var("MODEL_CONTEXT_WINDOW")
+            .ok()
+            .and_then(|value| value.parse().ok())
+        {
+            counter.context_window = window;
+        }
+        if let Some(reserved) = env::var("MODEL_RESERVED_TOKENS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+        {
+            counter.reserved_tokens = reserved;
+        }
+        counter
+    }
+
+    /// Tokens available for the prompt once the answer's share is reserved.
+    pub fn budget(&self) -> usize {
+        self.context_window.saturating_sub(self.reserved_tokens)
+    }
+
+    /// Estimated tokens of one chat message with `content`. CJK and other
+    /// non-ASCII characters are counted as a token each.
+    pub fn count(&self, content: &str) -> usize {
+        let ascii = content.chars().filter(char::is_ascii).count();
+        let other = content.chars().count() - ascii;
+        ascii.div_ceil(self.ascii_chars_per_token) + other + MESSAGE_OVERHEAD
+    }
+
+    /// Number of leading `turns` that must be dropped so that `fixed` tokens
+    /// (system prompt, newest user turn, ...) plus the rest fit the budget.
+    pub fn overflowing_turns(&self, fixed: usize, turns: &[usize]) -> usize {
+        let mut total = fixed + turns.iter().sum::<usize>();
+        let mut dropped = 0;
+        while total > self.budget() && dropped < turns.len() {
+            total -= turns[dropped];
+            dropped += 1;
+        }
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_model_prefers_specific_prefix() {
+        assert_eq!(
+            TokenCounter::for_model("gpt-4o-mini").context_window,
+            128_000
+        );
+        assert_eq!(TokenCounter::for_model("gpt-4-0613").context_window, 8_192);
+        assert_eq!(TokenCounter::for_model("llama3").context_window, 8_192);
+    }
+
+    #[test]
+    fn test_count() {
+        let counter = TokenCounter::for_model("gpt-4o");
+        assert_eq!(counter.count(""), MESSAGE_OVERHEAD);
+        assert_eq!(counter.count("abcdefgh"), 2 + MESSAGE_OVERHEAD);
+        assert_eq!(counter.count("你好 ab"), 2 + 1 + MESSAGE_OVERHEAD);
+    }
+
+    #[test]
+    fn test_overflowing_turns() {
+        let counter = TokenCounter {
+            context_window: 100,
+            reserved_tokens: 20,
+            ascii_chars_per_token: 4,
+        };
+
+        assert_eq!(counter.overflowing_turns(30, &[10, 10, 10]), 0);
+        assert_eq!(counter.overflowing_turns(30, &[30, 20, 10]), 1);
+        assert_eq!(counter.overflowing_turns(30, &[30, 30, 30]), 2);
+        assert_eq!(counter.overflowing_turns(90, &[10, 10]), 2);
+    }
+}
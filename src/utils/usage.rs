@@ -0,0 +1,366 @@
+use chrono::{Days, NaiveDate, Utc};
+use redis::{AsyncCommands, RedisResult};
+use serde::Deserialize;
+use serenity::model::id::{GuildId, UserId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+};
+use tracing::error;
+
+use crate::utils::{openai::ChatGPTResponse, redis_client::RedisManager};
+
+/// Daily usage is kept a little over a month.
+const USAGE_TTL_SECONDS: usize = 60 * 60 * 24 * 35;
+
+/// Tokens and images spent on one model.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UsageRecord {
+    pub model: String,
+    pub prompt_tokens: u64,
+    /// Part of `prompt_tokens` served from the prompt cache.
+    pub cached_tokens: u64,
+    pub completion_tokens: u64,
+    pub images: u64,
+}
+
+impl UsageRecord {
+    pub fn from_response(response: &ChatGPTResponse) -> Self {
+        let usage = &response.usage;
+        UsageRecord {
+            model: response.model.clone(),
+            prompt_tokens: usage.prompt_tokens.into(),
+            cached_tokens: usage
+                .prompt_tokens_details
+                .as_ref()
+                .map_or(0, |details| details.cached_tokens.into()),
+            completion_tokens: usage.completion_tokens.into(),
+            images: 0,
+        }
+    }
+
+    pub fn images(model: &str, images: usize) -> Self {
+        UsageRecord {
+            model: model.to_string(),
+            images: images as u64,
+            ..Default::default()
+        }
+    }
+
+    pub fn tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn fields(&self) -> [(&'static str, u64); 4] {
+        [
+            ("prompt", self.prompt_tokens),
+            ("cached", self.cached_tokens),
+            ("completion", self.completion_tokens),
+            ("images", self.images),
+        ]
+    }
+
+    fn add(&mut self, kind: &str, value: u64) {
+        match kind {
+            "prompt" => self.prompt_tokens += value,
+            "cached" => self.cached_tokens += value,
+            "completion" => self.completion_tokens += value,
+            "images" => self.images += value,
+            _ => {}
+        }
+    }
+
+    /// Estimated cost in USD, if the model has a known price.
+    pub fn cost(&self) -> Option<f64> {
+        let price = get_price(&self.model)?;
+        let uncached = self.prompt_tokens.saturating_sub(self.cached_tokens);
+        let tokens = uncached as f64 * price.prompt
+            + self.cached_tokens as f64 * price.cached.unwrap_or(price.prompt)
+            + self.completion_tokens as f64 * price.completion;
+        Some(tokens / 1_000_000.0 + self.images as f64 * price.image)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UsageScope {
+    User(UserId),
+    Guild(GuildId),
+}
+
+impl UsageScope {
+    fn key(&self, day: NaiveDate) -> String {
+        let day = day.format("%Y%m%d");
+        match self {
+            UsageScope::User(user_id) => format!("usage_{}_user_{}", day, user_id),
+            UsageScope::Guild(guild_id) => format!("usage_{}_guild_{}", day, guild_id),
+        }
+    }
+
+    fn quota_var(&self) -> &'static str {
+        match self {
+            UsageScope::User(_) => "DAILY_USER_TOKEN_QUOTA",
+            UsageScope::Guild(_) => "DAILY_GUILD_TOKEN_QUOTA",
+        }
+    }
+}
+
+fn scopes_for(user_id: UserId, guild_id: Option<GuildId>) -> Vec<UsageScope> {
+    let mut scopes = vec![UsageScope::User(user_id)];
+    scopes.extend(guild_id.map(UsageScope::Guild));
+    scopes
+}
+
+fn today() -> NaiveDate {
+    Utc::now().date_naive()
+}
+
+/// Adds `record` to today's totals of the user and of the guild it was
+/// spent in, under the model's name.
+pub async fn record_usage(
+    conn: &mut RedisManager,
+    user_id: UserId,
+    guild_id: Option<GuildId>,
+    record: &UsageRecord,
+) -> RedisResult<()> {
+    let mut pipe = redis::pipe();
+    for scope in scopes_for(user_id, guild_id) {
+        let key = scope.key(today());
+        for (kind, value) in record.fields() {
+            if value > 0 {
+                pipe.hincr(&key, format!("{}:{}", record.model, kind), value)
+                    .ignore();
+            }
+        }
+        pipe.expire(&key, USAGE_TTL_SECONDS).ignore();
+    }
+    pipe.query_async(conn).await
+}
+
+/// Like [`record_usage`], but only logs failures: losing a bit of accounting
+/// shouldn't stop an answer from going out.
+pub async fn try_record_usage(
+    conn: &mut RedisManager,
+    user_id: UserId,
+    guild_id: Option<GuildId>,
+    records: &[UsageRecord],
+) {
+    for record in records {
+        if let Err(e) = record_usage(conn, user_id, guild_id, record).await {
+            error!("Failed to record usage: {}", e);
+        }
+    }
+}
+
+/// Folds `model:kind` hash fields into per-model records.
+fn merge_fields(totals: &mut BTreeMap<String, UsageRecord>, fields: HashMap<String, u64>) {
+    for (field, value) in fields {
+        let Some((model, kind)) = field.rsplit_once(':') else {
+            continue;
+        };
+        totals
+            .entry(model.to_string())
+            .or_insert_with(|| UsageRecord {
+                model: model.to_string(),
+                ..Default::default()
+            })
+            .add(kind, value);
+    }
+}
+
+/// Totals per model over the last `days` days, today included.
+pub async fn get_usage(
+    conn: &mut RedisManager,
+    scope: UsageScope,
+    days: u64,
+) -> RedisResult<Vec<UsageRecord>> {
+    let mut totals = BTreeMap::new();
+    for offset in 0..days {
+        let Some(day) = today().checked_sub_days(Days::new(offset)) else {
+            break;
+        };
+        let fields: HashMap<String, u64> = conn.hgetall(scope.key(day)).await?;
+        merge_fields(&mut totals, fields);
+    }
+    Ok(totals.into_values().collect())
+}
+
+/// Checks today's token totals against `DAILY_USER_TOKEN_QUOTA` and
+/// `DAILY_GUILD_TOKEN_QUOTA`, either of which may be unset for no limit.
+/// Returns the message to show when one is used up.
+pub async fn check_quota(
+    conn: &mut RedisManager,
+    user_id: UserId,
+    guild_id: Option<GuildId>,
+) -> Result<(), String> {
+    for scope in scopes_for(user_id, guild_id) {
+        let Some(quota) = env::var(scope.quota_var())
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|quota| *quota > 0)
+        else {
+            continue;
+        };
+
+        let used: u64 = match get_usage(conn, scope, 1).await {
+            Ok(records) => records.iter().map(UsageRecord::tokens).sum(),
+            Err(e) => {
+                // Rather answer too much than lock everyone out
+                error!("Failed to check quota: {}", e);
+                continue;
+            }
+        };
+        if used >= quota {
+            return Err(match scope {
+                UsageScope::User(_) => {
+                    "You have used up your daily quota, please try again tomorrow.".to_string()
+                }
+                UsageScope::Guild(_) => {
+                    "This server has used up its daily quota, please try again tomorrow."
+                        .to_string()
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+/// USD per million tokens, and per image for image models.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+    /// Price of cached prompt tokens, the prompt price when unset.
+    pub cached: Option<f64>,
+    pub image: f64,
+}
+
+/// Price for `model`, from `MODEL_PRICES` when it lists the model, otherwise
+/// from the built-in table. `MODEL_PRICES` is a JSON object keyed by model
+/// prefix, e.g. `{"gpt-4o": {"prompt": 2.5, "completion": 10}}`.
+pub fn get_price(model: &str) -> Option<Price> {
+    let configured: HashMap<String, Price> = env::var("MODEL_PRICES")
+        .ok()
+        .and_then(|value| serde_json::from_str(&value).ok())
+        .unwrap_or_default();
+    find_price(&configured, model)
+}
+
+fn find_price(configured: &HashMap<String, Price>, model: &str) -> Option<Price> {
+    // (model prefix, prompt, completion, cached, image), most specific first
+    const PRICES: &[(&str, f64, f64, Option<f64>, f64)] = &[
+        ("gpt-4o-mini", 0.15, 0.6, Some(0.075), 0.0),
+        ("gpt-4o", 2.5, 10.0, Some(1.25), 0.0),
+        ("gpt-4.1-mini", 0.4, 1.6, Some(0.1), 0.0),
+        ("gpt-4.1", 2.0, 8.0, Some(0.5), 0.0),
+        ("gpt-4-turbo", 10.0, 30.0, None, 0.0),
+        ("gpt-4", 30.0, 60.0, None, 0.0),
+        ("gpt-3.5-turbo", 0.5, 1.5, None, 0.0),
+        ("dall-e-3", 0.0, 0.0, None, 0.04),
+        ("dall-e-2", 0.0, 0.0, None, 0.02),
+    ];
+
+    let configured = configured
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, price)| *price);
+    configured.or_else(|| {
+        PRICES
+            .iter()
+            .find(|(prefix, ..)| model.starts_with(prefix))
+            .map(|&(_, prompt, completion, cached, image)| Price {
+                prompt,
+                completion,
+                cached,
+                image,
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_key() {
+        let day = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+        assert_eq!(
+            UsageScope::User(UserId(1)).key(day),
+            "usage_20240309_user_1"
+        );
+        assert_eq!(
+            UsageScope::Guild(GuildId(2)).key(day),
+            "usage_20240309_guild_2"
+        );
+    }
+
+    #[test]
+    fn test_merge_fields() {
+        let mut totals = BTreeMap::new();
+        merge_fields(
+            &mut totals,
+            HashMap::from([
+                ("gpt-4o:prompt".to_string(), 100),
+                ("gpt-4o:completion".to_string(), 20),
+                ("dall-e-3:images".to_string(), 2),
+            ]),
+        );
+        merge_fields(
+            &mut totals,
+            HashMap::from([
+                ("gpt-4o:prompt".to_string(), 50),
+                ("gpt-4o:cached".to_string(), 30),
+            ]),
+        );
+
+        let gpt = &totals["gpt-4o"];
+        assert_eq!(gpt.prompt_tokens, 150);
+        assert_eq!(gpt.cached_tokens, 30);
+        assert_eq!(gpt.completion_tokens, 20);
+        assert_eq!(gpt.tokens(), 170);
+        assert_eq!(totals["dall-e-3"].images, 2);
+    }
+
+    #[test]
+    fn test_find_price() {
+        let configured = HashMap::new();
+        assert_eq!(
+            find_price(&configured, "gpt-4o-mini-2024-07-18")
+                .unwrap()
+                .prompt,
+            0.15
+        );
+        assert_eq!(
+            find_price(&configured, "gpt-4o-2024-08-06").unwrap().prompt,
+            2.5
+        );
+        assert!(find_price(&configured, "llama3").is_none());
+
+        let configured = HashMap::from([(
+            "llama".to_string(),
+            Price {
+                prompt: 0.1,
+                ..Default::default()
+            },
+        )]);
+        assert_eq!(find_price(&configured, "llama3").unwrap().prompt, 0.1);
+    }
+
+    #[test]
+    fn test_cost() {
+        let record = UsageRecord {
+            model: "gpt-4o".to_string(),
+            prompt_tokens: 1_000_000,
+            cached_tokens: 500_000,
+            completion_tokens: 100_000,
+            images: 0,
+        };
+        let cost = record.cost().unwrap();
+        assert!((cost - (1.25 + 0.625 + 1.0)).abs() < 1e-9);
+
+        let record = UsageRecord::images("dall-e-3", 3);
+        assert!((record.cost().unwrap() - 0.12).abs() < 1e-9);
+        assert!(UsageRecord::images("unknown", 1).cost().is_none());
+    }
+}